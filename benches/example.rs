@@ -1,9 +1,102 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
 use rustyhello::{UServiceConfig, UService, start_async, send_http_kill};
-use rustyhello::k8slifecycle::{HealthCheck};
+use rustyhello::k8slifecycle::{HealthCheck, HealthProbe};
+use rustyhello::exposition::{Encoding, Exposition, Format};
+use rustyhello::metrics::Metrics;
 
 
+pub fn health_benchmark(c: &mut Criterion) {
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let probes = 5000;
+    let threads = 4;
+
+    let check = HealthCheck::new("bench");
+    for i in 0..probes {
+        check.add(&HealthProbe::new(&format!("probe{}", i), Duration::from_secs(60)));
+    }
+
+    c.bench_function("health status", |b| {
+        b.iter(|| black_box(check.status().0));
+    });
+
+    // Keep other threads evaluating the same HealthCheck while measuring
+    let running = Arc::new(AtomicBool::new(true));
+    let load: Vec<_> = (0..threads)
+        .map(|_| {
+            let check = check.clone();
+            let running = running.clone();
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    black_box(check.status().0);
+                }
+            })
+        })
+        .collect();
+
+    c.bench_function("health status concurrent", |b| {
+        b.iter(|| black_box(check.status().0));
+    });
+
+    running.store(false, Ordering::Relaxed);
+    for handle in load {
+        handle.join().expect("Load thread complete");
+    }
+}
+
+
+pub fn metrics_benchmark(c: &mut Criterion) {
+
+    use prometheus::Encoder;
+    use std::time::Duration;
+
+    let series = 20000;
+
+    let metrics = Metrics::new("bench", "bench");
+    let requests = metrics
+        .counter_vec("bench_requests", "Requests by customer", &["customer"])
+        .unwrap();
+    for i in 0..series {
+        requests.with_label_values(&[&format!("customer{}", i)]).inc();
+    }
+
+    // Encoding each registry into its own String and concatenating, as the endpoint used to
+    c.bench_function("metrics concatenated", |b| {
+        b.iter(|| {
+            let encoder = prometheus::TextEncoder::new();
+            let mut buffer = Vec::new();
+            encoder.encode(&metrics.registry().gather(), &mut buffer).unwrap();
+            let mut res = String::from_utf8(buffer.clone()).unwrap();
+            let mut buffer = Vec::new();
+            encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+            res.push_str(&String::from_utf8(buffer.clone()).unwrap());
+            black_box(res)
+        });
+    });
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let exposition = Exposition::with_cache(&metrics, Duration::ZERO);
+    for (name, encoding) in [
+        ("metrics identity", Encoding::Identity),
+        ("metrics gzip", Encoding::Gzip),
+        ("metrics zstd", Encoding::Zstd),
+    ] {
+        c.bench_function(name, |b| {
+            b.iter(|| black_box(rt.block_on(exposition.render(Format::Text, encoding))));
+        });
+    }
+
+    let cached = Exposition::with_cache(&metrics, Duration::from_secs(1));
+    c.bench_function("metrics cached", |b| {
+        b.iter(|| black_box(rt.block_on(cached.render(Format::Text, Encoding::Gzip))));
+    });
+}
+
 
 pub fn http_benchmark(c: &mut Criterion) {
 
@@ -32,9 +125,7 @@ pub fn http_benchmark(c: &mut Criterion) {
             let local = tokio::task::LocalSet::new();
 
             local.spawn_local( async {
-                let config = UServiceConfig {
-                    name: String::from("test0"),
-                };
+                let config = UServiceConfig::new("test0");
                 let uservice = UService::new(&config.name);
                 let liveness = HealthCheck::new("liveness");
                 let readyness = HealthCheck::new("readyness");
@@ -101,5 +192,7 @@ pub fn http_benchmark(c: &mut Criterion) {
 
 criterion_group!(benches,
     // criterion_benchmark,
+     health_benchmark,
+     metrics_benchmark,
      http_benchmark);
 criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! Capture build information for the `buildinfo` module
+//!
+//! Sets `USERVICE_GIT_COMMIT`, `USERVICE_BUILD_TIMESTAMP`, `USERVICE_RUSTC_VERSION` and `USERVICE_FEATURES` for `env!`.
+//! The commit can be given by `GIT_COMMIT` where the build has no git checkout (eg a docker build) and
+//! the timestamp by `SOURCE_DATE_EPOCH` for reproducible builds.
+
+use std::env;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string())
+}
+
+fn main() {
+    let commit = env::var("GIT_COMMIT")
+        .ok()
+        .or_else(|| command_output("git", &["rev-parse", "HEAD"]))
+        .unwrap_or_else(|| String::from("unknown"));
+
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| String::from("unknown"));
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=USERVICE_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=USERVICE_BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rustc-env=USERVICE_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=USERVICE_FEATURES={}", features.join(","));
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    // Source changes rebuild the crate, so the timestamp and commit are captured again
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
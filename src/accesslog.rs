@@ -0,0 +1,589 @@
+//! Access log of the http servers
+//!
+//! Wrap a warp filter with [with_access_log] to write one entry per request it handles,
+//! either as a JSON object with a configurable list of [FIELDS] or in the Apache combined format.
+//!
+//! Entries are written straight to their [AccessLogOutput] so they are not subject to the log filter,
+//! or through the tracing subscriber under the `access` target when the output is [AccessLogOutput::Log].
+//! Requests can be sampled, and paths polled by the kubelet excluded, to keep the access log small.
+//! Server errors are always logged.
+
+use crate::env_parse;
+use crate::httpmetrics;
+use crate::logfile::{LogFile, LogFileConfig};
+use crate::logging::{self, Connection, REQUEST_ID_HEADER};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use warp::http::{HeaderMap, Method, Version};
+use warp::hyper::body::HttpBody;
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// Fields that can be written to a JSON access log entry
+pub const FIELDS: &[&str] = &[
+    "timestamp",
+    "server",
+    "remote_addr",
+    "method",
+    "path",
+    "query",
+    "protocol",
+    "status",
+    "bytes",
+    "latency_ms",
+    "referer",
+    "user_agent",
+    "request_id",
+    "trace_id",
+];
+
+/// Paths of the kubelet probes, excluded by default
+pub const PROBE_PATHS: &[&str] = &["/health/alive", "/health/ready"];
+
+/// Format of access log entries
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum AccessLogFormat {
+    /// One JSON object per line with the configured fields
+    Json,
+    /// Apache combined log format
+    Combined,
+}
+
+impl AccessLogFormat {
+    /// Parse `json` or `combined`
+    pub fn parse(format: &str) -> Option<AccessLogFormat> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "json" => Some(AccessLogFormat::Json),
+            "combined" => Some(AccessLogFormat::Combined),
+            _ => None,
+        }
+    }
+}
+
+/// Where access log entries are written
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum AccessLogOutput {
+    Stderr,
+    Stdout,
+    /// Appended to a file, reopened on `SIGHUP`
+    File(PathBuf),
+    /// Through the tracing subscriber at info level under the `access` target
+    Log,
+}
+
+impl AccessLogOutput {
+    /// Parse `stderr`, `stdout`, `log` or otherwise the path of a file
+    pub fn parse(output: &str) -> Option<AccessLogOutput> {
+        match output.trim() {
+            "" => None,
+            "stderr" => Some(AccessLogOutput::Stderr),
+            "stdout" => Some(AccessLogOutput::Stdout),
+            "log" => Some(AccessLogOutput::Log),
+            path => Some(AccessLogOutput::File(PathBuf::from(path))),
+        }
+    }
+}
+
+/// Parse a comma separated list of [FIELDS]
+pub fn parse_fields(fields: &str) -> Option<Vec<String>> {
+    fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| FIELDS.contains(&field).then(|| field.to_string()))
+        .collect()
+}
+
+/// Configuration of the access log
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AccessLogConfig {
+    /// Format of entries
+    pub format: AccessLogFormat,
+    /// Fields of JSON entries, in [FIELDS]
+    pub fields: Vec<String>,
+    /// Fraction of requests logged, between 0 and 1
+    pub sample: f64,
+    /// Paths of requests not logged
+    pub exclude: Vec<String>,
+    /// Where entries are written
+    pub output: AccessLogOutput,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> AccessLogConfig {
+        AccessLogConfig {
+            format: AccessLogFormat::Combined,
+            fields: FIELDS.iter().map(|field| field.to_string()).collect(),
+            sample: 1.0,
+            exclude: PROBE_PATHS.iter().map(|path| path.to_string()).collect(),
+            output: AccessLogOutput::Stderr,
+        }
+    }
+}
+
+impl AccessLogConfig {
+    /// Create an [AccessLogConfig] with settings overridden by environment variables
+    ///
+    ///  * `USERVICE_ACCESS_LOG_FORMAT` as `json` or `combined`, defaulting to `combined` in the `dev` environment and `json` elsewhere
+    ///  * `USERVICE_ACCESS_LOG_FIELDS` as a comma separated list of [FIELDS]
+    ///  * `USERVICE_ACCESS_LOG_SAMPLE` as the fraction of requests logged
+    ///  * `USERVICE_ACCESS_LOG_EXCLUDE` as a comma separated list of paths, empty to log every path
+    ///  * `USERVICE_ACCESS_LOG_OUTPUT` as `stderr`, `stdout`, `log` or the path of a file
+    pub fn from_env() -> AccessLogConfig {
+        let mut config = AccessLogConfig::default();
+        if !env::var("USERVICE_ENV").map_or(true, |env| env == "dev") {
+            config.format = AccessLogFormat::Json;
+        }
+        if let Some(format) = env_parse("USERVICE_ACCESS_LOG_FORMAT", AccessLogFormat::parse) {
+            config.format = format;
+        }
+        if let Some(fields) = env_parse("USERVICE_ACCESS_LOG_FIELDS", parse_fields) {
+            config.fields = fields;
+        }
+        if let Some(sample) = env_parse("USERVICE_ACCESS_LOG_SAMPLE", |v| {
+            v.parse::<f64>().ok().filter(|v| (0.0..=1.0).contains(v))
+        }) {
+            config.sample = sample;
+        }
+        if let Ok(exclude) = env::var("USERVICE_ACCESS_LOG_EXCLUDE") {
+            config.exclude = exclude
+                .split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some(output) = env_parse("USERVICE_ACCESS_LOG_OUTPUT", AccessLogOutput::parse) {
+            config.output = output;
+        }
+        config
+    }
+}
+
+/// Destination of the entries
+enum Sink {
+    Log,
+    Writer(Mutex<Box<dyn Write + Send>>),
+}
+
+/// Writes access log entries as configured
+#[derive(Clone)]
+pub struct AccessLog {
+    config: Arc<AccessLogConfig>,
+    sink: Arc<Sink>,
+}
+
+impl AccessLog {
+    /// Create an [AccessLog] opening its output
+    ///
+    /// A file that cannot be opened is reported and entries are written to stderr instead.
+    pub fn new(config: &AccessLogConfig) -> AccessLog {
+        let writer: Box<dyn Write + Send> = match &config.output {
+            AccessLogOutput::Log => {
+                return AccessLog {
+                    config: Arc::new(config.clone()),
+                    sink: Arc::new(Sink::Log),
+                }
+            }
+            AccessLogOutput::Stderr => Box::new(io::stderr()),
+            AccessLogOutput::Stdout => Box::new(io::stdout()),
+            AccessLogOutput::File(path) => match LogFile::open(&LogFileConfig::new(path)) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    warn!("Could not open access log {}: {}", path.display(), e);
+                    Box::new(io::stderr())
+                }
+            },
+        };
+        AccessLog::with_writer(config, writer)
+    }
+
+    /// Create an [AccessLog] writing entries to a writer whatever the configured output
+    pub fn with_writer(config: &AccessLogConfig, writer: Box<dyn Write + Send>) -> AccessLog {
+        AccessLog {
+            config: Arc::new(config.clone()),
+            sink: Arc::new(Sink::Writer(Mutex::new(writer))),
+        }
+    }
+
+    /// Write the entry of a request if it is not excluded or sampled out
+    fn record(&self, server: &str, request: &Request, response: &Response) {
+        let status = response.status();
+        if !status.is_server_error()
+            && (self
+                .config
+                .exclude
+                .iter()
+                .any(|path| path == request.path.as_str())
+                || rand::random::<f64>() >= self.config.sample)
+        {
+            return;
+        }
+
+        let line = match self.config.format {
+            AccessLogFormat::Json => self.json(server, request, response),
+            AccessLogFormat::Combined => combined(request, response),
+        };
+        match &*self.sink {
+            Sink::Log => info!(target: "access", "{}", line),
+            Sink::Writer(writer) => {
+                if let Err(e) = writeln!(writer.lock().unwrap(), "{}", line) {
+                    warn!("Could not write access log: {}", e);
+                }
+            }
+        }
+    }
+
+    /// JSON object of the configured fields of a request
+    fn json(&self, server: &str, request: &Request, response: &Response) -> String {
+        let mut entry = Map::new();
+        for field in self.config.fields.iter() {
+            let value = match field.as_str() {
+                "timestamp" => {
+                    let mut timestamp = String::new();
+                    tracing_subscriber::fmt::time::SystemTime
+                        .format_time(&mut Writer::new(&mut timestamp))
+                        .ok();
+                    Value::from(timestamp)
+                }
+                "server" => Value::from(server),
+                "remote_addr" => Value::from(
+                    request
+                        .connection
+                        .map(|connection| connection.remote_addr.to_string()),
+                ),
+                "method" => Value::from(request.method.as_str()),
+                "path" => Value::from(request.path.as_str()),
+                "query" => Value::from(request.query.as_str()),
+                "protocol" => Value::from(format!("{:?}", request.version())),
+                "status" => Value::from(response.status().as_u16()),
+                "bytes" => Value::from(response_size(response)),
+                "latency_ms" => Value::from(request.start.elapsed().as_secs_f64() * 1000.0),
+                "referer" => Value::from(request.header("referer")),
+                "user_agent" => Value::from(request.header("user-agent")),
+                "request_id" => Value::from(request.header(REQUEST_ID_HEADER)),
+                "trace_id" => Value::from(request.trace_id.as_deref()),
+                _ => continue,
+            };
+            entry.insert(field.clone(), value);
+        }
+        Value::Object(entry).to_string()
+    }
+}
+
+/// The request being handled
+struct Request {
+    start: Instant,
+    method: Method,
+    path: FullPath,
+    query: String,
+    headers: HeaderMap,
+    connection: Option<Connection>,
+    trace_id: Option<String>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)?.to_str().ok()
+    }
+
+    /// Version of the request, HTTP/1.1 if it was not served by [serve](crate::logging::serve)
+    fn version(&self) -> Version {
+        self.connection
+            .map_or(Version::HTTP_11, |connection| connection.version)
+    }
+}
+
+/// Size of the response body if known
+fn response_size(response: &Response) -> Option<u64> {
+    response.body().size_hint().exact().or_else(|| {
+        response
+            .headers()
+            .get(warp::http::header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+    })
+}
+
+/// Apache combined log format line of a request
+fn combined(request: &Request, response: &Response) -> String {
+    let query = if request.query.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", request.query)
+    };
+    format!(
+        "{} - - [{}] \"{} {}{} {:?}\" {} {} \"{}\" \"{}\"",
+        request.connection.map_or_else(
+            || String::from("-"),
+            |connection| connection.remote_addr.ip().to_string()
+        ),
+        clf_time(SystemTime::now()),
+        request.method,
+        escape(request.path.as_str()),
+        escape(&query),
+        request.version(),
+        response.status().as_u16(),
+        response_size(response).map_or_else(|| String::from("-"), |size| size.to_string()),
+        escape(request.header("referer").unwrap_or("-")),
+        escape(request.header("user-agent").unwrap_or("-")),
+    )
+}
+
+/// Escape a value written between quotes as Apache does, so it cannot end the quotes or the line
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Time in the common log format eg `10/Oct/2000:13:55:36 +0000`, in UTC
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Wrap a filter so that every request it handles is written to the access log under the name of the server
+///
+/// Rejections of the filter are answered by [recover](crate::httpmetrics::recover) and logged with their status,
+/// so the wrapped filter never rejects and should hold all the routes of a server.
+pub fn with_access_log<F, T>(
+    filter: F,
+    log: AccessLog,
+    server: &'static str,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .and(warp::ext::optional::<Connection>())
+        .map(
+            |method, path, query, headers: HeaderMap, connection| Request {
+                start: Instant::now(),
+                method,
+                path,
+                query,
+                trace_id: logging::trace_id(&headers),
+                headers,
+                connection,
+            },
+        )
+        .and(
+            filter
+                .map(Reply::into_response)
+                .recover(httpmetrics::recover)
+                .unify(),
+        )
+        .and_then(move |request: Request, response: Response| {
+            log.record(server, &request, &response);
+            async move { Ok::<_, Rejection>(response) }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::StatusCode;
+
+    /// Writer capturing the entries of a test
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn lines(&self) -> Vec<String> {
+            let captured = self.0.lock().unwrap();
+            std::str::from_utf8(&captured)
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    fn route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        warp::path!("health" / String).map(|probe: String| {
+            let status = if probe == "fail" {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            };
+            warp::reply::with_status(probe, status)
+        })
+    }
+
+    #[tokio::test]
+    async fn json_fields_excluded_and_sampled() {
+        //! Test that JSON entries carry the configured fields, probes and sampled out requests are skipped and rejections logged
+        let captured = Captured::default();
+        let config = AccessLogConfig {
+            format: AccessLogFormat::Json,
+            fields: parse_fields("method, path,query,status,bytes,request_id").unwrap(),
+            exclude: vec![String::from("/health/alive"), String::from("/health/fail")],
+            ..AccessLogConfig::default()
+        };
+        let filter = with_access_log(
+            route(),
+            AccessLog::with_writer(&config, Box::new(captured.clone())),
+            "health",
+        );
+
+        for path in ["/health/alive", "/health/info?verbose=1", "/health/fail"] {
+            warp::test::request()
+                .path(path)
+                .header(REQUEST_ID_HEADER, "abc")
+                .reply(&filter)
+                .await;
+        }
+        let lines = captured.lines();
+        assert_eq!(lines.len(), 2);
+        let entry: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(
+            entry,
+            serde_json::json!({
+                "method": "GET",
+                "path": "/health/info",
+                "query": "verbose=1",
+                "status": 200,
+                "bytes": 4,
+                "request_id": "abc",
+            })
+        );
+        let entry: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(entry["status"], 503);
+
+        let sampled = AccessLogConfig {
+            sample: 0.0,
+            ..config.clone()
+        };
+        let filter = with_access_log(
+            route(),
+            AccessLog::with_writer(&sampled, Box::new(captured.clone())),
+            "health",
+        );
+        warp::test::request()
+            .path("/health/info")
+            .reply(&filter)
+            .await;
+        warp::test::request()
+            .path("/health/fail")
+            .reply(&filter)
+            .await;
+        assert_eq!(captured.lines().len(), 3);
+
+        let filter = with_access_log(
+            route(),
+            AccessLog::with_writer(&config, Box::new(captured.clone())),
+            "health",
+        );
+        let response = warp::test::request().path("/unknown").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let entry: Value = serde_json::from_str(&captured.lines()[3]).unwrap();
+        assert_eq!(entry["path"], "/unknown");
+        assert_eq!(entry["status"], 404);
+        assert_eq!(parse_fields("method,cookie"), None);
+    }
+
+    #[tokio::test]
+    async fn combined_format() {
+        //! Test that combined entries follow the Apache combined log format with quoted values escaped
+        let captured = Captured::default();
+        let filter = with_access_log(
+            route(),
+            AccessLog::with_writer(&AccessLogConfig::default(), Box::new(captured.clone())),
+            "sample",
+        );
+        warp::test::request()
+            .path("/health/info?verbose=1")
+            .header("user-agent", "curl/8.0")
+            .reply(&filter)
+            .await;
+
+        warp::test::request()
+            .path("/health/info")
+            .header("user-agent", "evil\" 200 0 \"-\\")
+            .reply(&filter)
+            .await;
+
+        let line = &captured.lines()[0];
+        assert!(line.starts_with("- - - ["), "{}", line);
+        assert!(
+            line.ends_with("] \"GET /health/info?verbose=1 HTTP/1.1\" 200 4 \"-\" \"curl/8.0\""),
+            "{}",
+            line
+        );
+        let quoted = &captured.lines()[1];
+        assert!(
+            quoted.ends_with(" \"-\" \"evil\\\" 200 0 \\\"-\\\\\""),
+            "{}",
+            quoted
+        );
+        assert_eq!(escape("a\tb"), "a\\x09b");
+        assert_eq!(
+            clf_time(UNIX_EPOCH + std::time::Duration::from_secs(971186136)),
+            "10/Oct/2000:13:55:36 +0000"
+        );
+        assert_eq!(
+            AccessLogOutput::parse("/var/log/access.log"),
+            Some(AccessLogOutput::File(PathBuf::from("/var/log/access.log")))
+        );
+    }
+}
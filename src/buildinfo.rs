@@ -0,0 +1,157 @@
+//! Build and runtime information about the service
+//!
+//! The build information is captured by `build.rs` and combined with the start time and a hash of the [UServiceConfig]
+//! into a [ServiceInfo]. It is reported by the `/health/info` endpoint and exported as the `build_info` and `start_time_seconds` gauges.
+
+use crate::metrics::Metrics;
+use crate::UServiceConfig;
+use prometheus::{Gauge, IntGauge, Opts};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Name of the crate
+pub const NAME: &str = env!("CARGO_PKG_NAME");
+/// Version of the crate
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Git commit the service was built from
+pub const GIT_COMMIT: &str = env!("USERVICE_GIT_COMMIT");
+/// Seconds since the unix epoch when the service was built
+pub const BUILD_TIMESTAMP: &str = env!("USERVICE_BUILD_TIMESTAMP");
+/// Version of rustc the service was built with
+pub const RUSTC_VERSION: &str = env!("USERVICE_RUSTC_VERSION");
+/// Cargo features enabled in the build, separated by commas
+pub const FEATURES: &str = env!("USERVICE_FEATURES");
+
+/// 64 bit FNV-1a hash, which unlike the std hashers is the same for every build
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Hash of the configuration serialised as JSON, so instances built with different toolchains agree
+fn config_hash(config: &UServiceConfig) -> String {
+    let json = serde_json::to_vec(config).unwrap_or_default();
+    format!("{:016x}", fnv1a(&json))
+}
+
+/// Information about a running service
+#[derive(Clone, Debug)]
+pub struct ServiceInfo {
+    /// Name of the service
+    pub service: String,
+    /// Time the service was started
+    pub start_time: SystemTime,
+    started: Instant,
+    /// Hash of the configuration of the service to spot differently configured instances
+    pub config_hash: String,
+}
+
+impl ServiceInfo {
+    /// Create the [ServiceInfo] of a service starting now
+    pub fn new(config: &UServiceConfig) -> ServiceInfo {
+        ServiceInfo {
+            service: config.name.clone(),
+            start_time: SystemTime::now(),
+            started: Instant::now(),
+            config_hash: config_hash(config),
+        }
+    }
+
+    /// Seconds since the unix epoch when the service was started
+    pub fn start_time_seconds(&self) -> f64 {
+        self.start_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+    }
+
+    /// Seconds since the service was started
+    pub fn uptime_seconds(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    /// Export the `build_info` and `start_time_seconds` gauges
+    pub fn register(&self, metrics: &Metrics) {
+        let build_info = IntGauge::with_opts(
+            Opts::new("build_info", "Build information of the service, always 1")
+                .const_label("crate", NAME)
+                .const_label("crate_version", VERSION)
+                .const_label("git_commit", GIT_COMMIT)
+                .const_label("rustc_version", RUSTC_VERSION)
+                .const_label("features", FEATURES)
+                .const_label("config_hash", &self.config_hash),
+        )
+        .expect("metric can be created");
+        build_info.set(1);
+        metrics.register(&build_info);
+
+        let start_time = Gauge::new(
+            "start_time_seconds",
+            "Start time of the service since unix epoch in seconds",
+        )
+        .expect("metric can be created");
+        start_time.set(self.start_time_seconds());
+        metrics.register(&start_time);
+    }
+}
+
+impl Serialize for ServiceInfo {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let features: Vec<&str> = FEATURES.split(',').filter(|f| !f.is_empty()).collect();
+        let mut map = serializer.serialize_map(Some(10))?;
+        map.serialize_entry("service", &self.service)?;
+        map.serialize_entry("name", NAME)?;
+        map.serialize_entry("version", VERSION)?;
+        map.serialize_entry("git_commit", GIT_COMMIT)?;
+        map.serialize_entry("build_timestamp", &BUILD_TIMESTAMP.parse::<u64>().ok())?;
+        map.serialize_entry("rustc_version", RUSTC_VERSION)?;
+        map.serialize_entry("features", &features)?;
+        map.serialize_entry("start_time", &self.start_time_seconds())?;
+        map.serialize_entry("uptime_seconds", &self.uptime_seconds())?;
+        map.serialize_entry("config_hash", &self.config_hash)?;
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info_exported() {
+        //! Test that the build information is exported and the config hash follows the configuration
+        let info = ServiceInfo::new(&UServiceConfig::new("info"));
+        assert_eq!(
+            info.config_hash,
+            ServiceInfo::new(&UServiceConfig::new("info")).config_hash
+        );
+        assert_ne!(
+            info.config_hash,
+            ServiceInfo::new(&UServiceConfig::new("other")).config_hash
+        );
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert!(!GIT_COMMIT.is_empty());
+        assert!(RUSTC_VERSION.starts_with("rustc"));
+
+        let metrics = Metrics::new("info", "test");
+        info.register(&metrics);
+        let families = metrics.registry().gather();
+        let build_info = families
+            .iter()
+            .find(|f| f.get_name() == "build_info")
+            .expect("build_info exported");
+        let metric = &build_info.get_metric()[0];
+        assert_eq!(metric.get_gauge().get_value(), 1.0);
+        assert!(metric
+            .get_label()
+            .iter()
+            .any(|l| l.get_name() == "crate_version" && l.get_value() == VERSION));
+        assert!(families
+            .iter()
+            .any(|f| f.get_name() == "start_time_seconds"));
+    }
+}
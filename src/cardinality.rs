@@ -0,0 +1,326 @@
+//! Guard against unbounded label cardinality
+//!
+//! A label fed from an unbounded source, such as a user id or a raw path, creates a new series for every value
+//! until Prometheus runs out of memory. A [Guarded] vector metric caps the number of distinct label sets it holds.
+//! Label sets beyond the cap are folded into a single series with every label set to [OVERFLOW],
+//! counted in `metric_label_overflow_total` and warned about once per metric.
+//!
+//! Limits are set per metric by full name in [MetricsConfig::cardinality_limits](crate::metrics::MetricsConfig::cardinality_limits).
+//! The http collectors are guarded by [HTTP_LIMIT] unless configured otherwise, other metrics only when configured.
+//! A limit of 0 turns the guard off.
+
+use prometheus::core::{Collector, Desc, MetricVec, MetricVecBuilder};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// Value of every label of the series that label sets beyond the limit are folded into
+pub const OVERFLOW: &str = "__overflow__";
+
+/// Default limit of label sets of each http collector
+pub const HTTP_LIMIT: usize = 1000;
+
+/// Parse limits given as `name=limit,name=limit`
+pub fn parse_limits(limits: &str) -> Option<BTreeMap<String, usize>> {
+    limits
+        .split(',')
+        .filter(|limit| !limit.trim().is_empty())
+        .map(|limit| {
+            let (name, limit) = limit.split_once('=')?;
+            Some((name.trim().to_string(), limit.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// The configured limits and the counter of label sets folded into the overflow series
+#[derive(Clone)]
+pub struct CardinalityLimits {
+    limits: BTreeMap<String, usize>,
+    overflow: IntCounterVec,
+}
+
+impl CardinalityLimits {
+    /// Create [CardinalityLimits] registering the overflow counter
+    pub fn register(
+        registry: &Registry,
+        limits: &BTreeMap<String, usize>,
+    ) -> prometheus::Result<CardinalityLimits> {
+        let overflow = IntCounterVec::new(
+            Opts::new(
+                "metric_label_overflow_total",
+                "Observations folded into the overflow series of a metric over its label limit",
+            ),
+            &["metric"],
+        )?;
+        registry.register(Box::new(overflow.clone()))?;
+        Ok(CardinalityLimits {
+            limits: limits.clone(),
+            overflow,
+        })
+    }
+
+    /// Guard a vector metric with its configured limit, or the default if it has none
+    pub fn guard<T: MetricVecBuilder>(
+        &self,
+        vec: MetricVec<T>,
+        name: &str,
+        default: Option<usize>,
+    ) -> Guarded<MetricVec<T>> {
+        let limit = self
+            .limits
+            .get(name)
+            .copied()
+            .or(default)
+            .filter(|limit| *limit > 0);
+        Guarded {
+            vec,
+            guard: limit.map(|limit| {
+                Arc::new(Guard {
+                    name: name.to_string(),
+                    limit,
+                    seen: Mutex::new(HashSet::new()),
+                    warned: AtomicBool::new(false),
+                    overflow: self.overflow.clone(),
+                })
+            }),
+        }
+    }
+}
+
+struct Guard {
+    name: String,
+    limit: usize,
+    /// Hashes of the label sets admitted
+    seen: Mutex<HashSet<u64>>,
+    warned: AtomicBool,
+    overflow: IntCounterVec,
+}
+
+fn label_hash(vals: &[&str]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    vals.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A vector metric holding at most a limited number of label sets
+///
+/// Only the label taking methods that go through the limit are exposed, the vector itself is not.
+#[derive(Clone)]
+pub struct Guarded<V> {
+    vec: V,
+    guard: Option<Arc<Guard>>,
+}
+
+impl<T: MetricVecBuilder> Guarded<MetricVec<T>> {
+    /// True if the label set has its own series rather than being folded into the overflow series
+    pub fn admits(&self, vals: &[&str]) -> bool {
+        match &self.guard {
+            None => true,
+            Some(guard) => {
+                let seen = guard.seen.lock().unwrap();
+                seen.len() < guard.limit || seen.contains(&label_hash(vals))
+            }
+        }
+    }
+
+    /// The metric of the label set, or of the overflow series when over the limit
+    ///
+    /// Panics if the number of values does not match the labels, as [MetricVec::with_label_values] does.
+    pub fn with_label_values(&self, vals: &[&str]) -> T::M {
+        self.get_metric_with_label_values(vals)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// The metric of the label set, or of the overflow series when over the limit
+    pub fn get_metric_with_label_values(&self, vals: &[&str]) -> prometheus::Result<T::M> {
+        let guard = match &self.guard {
+            Some(guard) if vals.iter().any(|val| *val != OVERFLOW) => guard,
+            _ => return self.vec.get_metric_with_label_values(vals),
+        };
+        // Check the label count before taking a place under the limit
+        let expect = self.vec.desc()[0].variable_labels.len();
+        if vals.len() != expect {
+            return Err(prometheus::Error::InconsistentCardinality {
+                expect,
+                got: vals.len(),
+            });
+        }
+        {
+            let mut seen = guard.seen.lock().unwrap();
+            let hash = label_hash(vals);
+            if seen.contains(&hash) || seen.len() < guard.limit && seen.insert(hash) {
+                return self.vec.get_metric_with_label_values(vals);
+            }
+        }
+
+        guard.overflow.with_label_values(&[&guard.name]).inc();
+        if !guard.warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                "{} has more than {} label sets, folding new label sets into {}",
+                guard.name, guard.limit, OVERFLOW
+            );
+        }
+        self.vec
+            .get_metric_with_label_values(&vec![OVERFLOW; vals.len()])
+    }
+
+    /// The metric of the labels by name, or of the overflow series when over the limit
+    ///
+    /// Panics if the labels do not match, as [MetricVec::with] does.
+    pub fn with(&self, labels: &HashMap<&str, &str>) -> T::M {
+        self.get_metric_with(labels)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// The metric of the labels by name, or of the overflow series when over the limit
+    pub fn get_metric_with(&self, labels: &HashMap<&str, &str>) -> prometheus::Result<T::M> {
+        self.get_metric_with_label_values(&self.values(labels)?)
+    }
+
+    /// Remove the series of the label set, freeing its place under the limit
+    pub fn remove_label_values(&self, vals: &[&str]) -> prometheus::Result<()> {
+        self.vec.remove_label_values(vals)?;
+        if let Some(guard) = &self.guard {
+            guard.seen.lock().unwrap().remove(&label_hash(vals));
+        }
+        Ok(())
+    }
+
+    /// Remove the series of the labels by name, freeing its place under the limit
+    pub fn remove(&self, labels: &HashMap<&str, &str>) -> prometheus::Result<()> {
+        self.remove_label_values(&self.values(labels)?)
+    }
+
+    /// Remove every series, freeing every place under the limit
+    pub fn reset(&self) {
+        self.vec.reset();
+        if let Some(guard) = &self.guard {
+            guard.seen.lock().unwrap().clear();
+        }
+    }
+
+    /// Values of the labels by name in the order of the labels of the vector
+    fn values<'a>(&self, labels: &HashMap<&str, &'a str>) -> prometheus::Result<Vec<&'a str>> {
+        let desc = self.vec.desc()[0];
+        if labels.len() != desc.variable_labels.len() {
+            return Err(prometheus::Error::InconsistentCardinality {
+                expect: desc.variable_labels.len(),
+                got: labels.len(),
+            });
+        }
+        desc.variable_labels
+            .iter()
+            .map(|name| {
+                labels.get(name.as_str()).copied().ok_or_else(|| {
+                    prometheus::Error::Msg(format!("label name {} missing in label map", name))
+                })
+            })
+            .collect()
+    }
+}
+
+impl<T: MetricVecBuilder> Collector for Guarded<MetricVec<T>> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.vec.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.vec.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_folded() {
+        //! Test that label sets beyond the limit are folded into the overflow series and counted
+        let registry = Registry::new();
+        let mut config = BTreeMap::new();
+        config.insert(String::from("requests_total"), 2);
+        let limits = CardinalityLimits::register(&registry, &config).unwrap();
+        let requests = limits.guard(
+            IntCounterVec::new(Opts::new("requests_total", "Requests"), &["user"]).unwrap(),
+            "requests_total",
+            None,
+        );
+        registry.register(Box::new(requests.clone())).unwrap();
+
+        for user in ["a", "b", "c", "d", "a"] {
+            requests.with_label_values(&[user]).inc();
+        }
+        assert_eq!(requests.with_label_values(&["a"]).get(), 2);
+        assert_eq!(requests.with_label_values(&[OVERFLOW]).get(), 2);
+        assert!(requests.admits(&["b"]) && !requests.admits(&["c"]));
+        assert_eq!(
+            limits.overflow.with_label_values(&["requests_total"]).get(),
+            2
+        );
+
+        requests.remove_label_values(&["b"]).unwrap();
+        requests.with_label_values(&["c"]).inc();
+        assert_eq!(requests.with_label_values(&["c"]).get(), 1);
+        let family = registry
+            .gather()
+            .into_iter()
+            .find(|f| f.get_name() == "requests_total")
+            .unwrap();
+        assert_eq!(family.get_metric().len(), 3);
+
+        let unguarded = limits.guard(
+            IntCounterVec::new(Opts::new("other_total", "Other"), &["user"]).unwrap(),
+            "other_total",
+            None,
+        );
+        assert!((0..10).all(|i| unguarded.admits(&[&i.to_string()])));
+        assert_eq!(
+            parse_limits("a=1, b = 20"),
+            Some(config_of(&[("a", 1), ("b", 20)]))
+        );
+        assert_eq!(parse_limits("a=x"), None);
+    }
+
+    #[test]
+    fn every_lookup_guarded() {
+        //! Test that looking up by label name and the fallible lookups are held to the limit too
+        let registry = Registry::new();
+        let limits =
+            CardinalityLimits::register(&registry, &config_of(&[("jobs_total", 1)])).unwrap();
+        let jobs = limits.guard(
+            IntCounterVec::new(Opts::new("jobs_total", "Jobs"), &["queue", "user"]).unwrap(),
+            "jobs_total",
+            None,
+        );
+        let labels = |user| -> HashMap<&str, &str> { [("queue", "q"), ("user", user)].into() };
+
+        jobs.with(&labels("a")).inc();
+        jobs.with(&labels("b")).inc();
+        jobs.get_metric_with_label_values(&["q", "c"])
+            .unwrap()
+            .inc();
+        assert_eq!(jobs.with_label_values(&[OVERFLOW, OVERFLOW]).get(), 2);
+        assert_eq!(jobs.get_metric_with(&labels("a")).unwrap().get(), 1);
+        assert!(jobs.get_metric_with_label_values(&["q"]).is_err());
+        assert!(jobs.get_metric_with(&[("queue", "q")].into()).is_err());
+
+        jobs.remove(&labels("a")).unwrap();
+        assert!(jobs.admits(&["q", "b"]));
+        jobs.with(&labels("b")).inc();
+        jobs.reset();
+        assert!(jobs.admits(&["q", "c"]));
+        assert_eq!(limits.overflow.with_label_values(&["jobs_total"]).get(), 2);
+    }
+
+    fn config_of(limits: &[(&str, usize)]) -> BTreeMap<String, usize> {
+        limits
+            .iter()
+            .map(|(name, limit)| (name.to_string(), *limit))
+            .collect()
+    }
+}
@@ -0,0 +1,424 @@
+//! Encoding of the metrics endpoint
+//!
+//! An [Exposition] gathers the registry of the service and the default registry into a single list of families and encodes
+//! them in one pass, straight into the compressor chosen from the `Accept-Encoding` of the scrape.
+//! Responses can be cached for a short window given by [MetricsConfig::scrape_cache](crate::metrics::MetricsConfig::scrape_cache)
+//! so several scrapers, or a scraper retrying, do not each pay for encoding a large registry.
+//! Scrapes arriving while the cached body is encoded wait for it, so only one encoding runs after the window expires.
+//! Without a cache window the encoder output is streamed to the scrape in chunks rather than built up in memory first.
+
+use crate::metrics::Metrics;
+use crate::openmetrics;
+use flate2::write::GzEncoder;
+use prometheus::proto::MetricFamily;
+use prometheus::{Encoder, TextEncoder};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, OnceCell};
+use tracing::warn;
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
+
+/// Format of the metrics response
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// Prometheus text format
+    Text,
+    /// OpenMetrics text format including exemplars
+    OpenMetrics,
+}
+
+impl Format {
+    /// Choose the format from the `Accept` header of a request
+    pub fn negotiate(accept: Option<&str>) -> Format {
+        match accept {
+            Some(accept) if accept.contains("application/openmetrics-text") => Format::OpenMetrics,
+            _ => Format::Text,
+        }
+    }
+
+    /// Value of the `content-type` header for the format
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Text => prometheus::TEXT_FORMAT,
+            Format::OpenMetrics => openmetrics::CONTENT_TYPE,
+        }
+    }
+}
+
+/// Compression of the metrics response
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// Choose the encoding from the `Accept-Encoding` header of a request
+    ///
+    /// The coding with the highest quality value is chosen, preferring zstd over gzip when equal.
+    /// Codings with a quality of 0 are refused.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Encoding {
+        let mut best = (Encoding::Identity, 0.0);
+        for coding in accept_encoding.unwrap_or_default().split(',') {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let encoding = match name.to_ascii_lowercase().as_str() {
+                "zstd" => Encoding::Zstd,
+                "gzip" | "x-gzip" => Encoding::Gzip,
+                _ => continue,
+            };
+            if quality > best.1
+                || (quality == best.1 && quality > 0.0 && encoding == Encoding::Zstd)
+            {
+                best = (encoding, quality);
+            }
+        }
+        best.0
+    }
+
+    /// Value of the `content-encoding` header, none for identity
+    pub fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Zstd => Some("zstd"),
+        }
+    }
+}
+
+/// A response body shared by every scrape of a cache generation, and when the generation started
+type Cached = (Instant, Arc<OnceCell<Bytes>>);
+
+/// Size of the chunks of a streamed response
+const CHUNK: usize = 64 * 1024;
+
+/// Encodes the metrics of a service for the metrics endpoint, caching the responses for a window
+#[derive(Clone)]
+pub struct Exposition {
+    metrics: Metrics,
+    window: Duration,
+    cache: Arc<Mutex<HashMap<(Format, Encoding), Cached>>>,
+}
+
+impl Exposition {
+    /// Create an [Exposition] of [Metrics] using the cache window of the [Metrics]
+    pub fn new(metrics: &Metrics) -> Exposition {
+        Exposition::with_cache(metrics, metrics.scrape_cache())
+    }
+
+    /// Create an [Exposition] of [Metrics] caching responses for a window, no caching if zero
+    pub fn with_cache(metrics: &Metrics, window: Duration) -> Exposition {
+        Exposition {
+            metrics: metrics.clone(),
+            window,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Body of a metrics response
+    ///
+    /// Without a cache window the encoder output is streamed as it is produced,
+    /// otherwise the body comes from [render](Exposition::render).
+    pub async fn body(&self, format: Format, encoding: Encoding) -> Body {
+        if self.window.is_zero() {
+            self.stream(format, encoding)
+        } else {
+            Body::from(self.render(format, encoding).await)
+        }
+    }
+
+    /// Body of a metrics response, from the cache if encoded within the window
+    ///
+    /// Scrapes arriving while a body is encoded wait for that body rather than each encoding their own.
+    pub async fn render(&self, format: Format, encoding: Encoding) -> Bytes {
+        if self.window.is_zero() {
+            return self.encode_blocking(format, encoding).await;
+        }
+        let cell = {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get(&(format, encoding)) {
+                Some((at, cell)) if at.elapsed() < self.window => cell.clone(),
+                _ => {
+                    let cell = Arc::new(OnceCell::new());
+                    cache.insert((format, encoding), (Instant::now(), cell.clone()));
+                    cell
+                }
+            }
+        };
+        cell.get_or_init(|| self.encode_blocking(format, encoding))
+            .await
+            .clone()
+    }
+
+    /// Stream the encoded metrics into a [Body] as they are encoded
+    fn stream(&self, format: Format, encoding: Encoding) -> Body {
+        let (sender, receiver) = mpsc::channel(4);
+        let exposition = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let chunks = Chunks {
+                sender: sender.clone(),
+                buffer: Vec::with_capacity(CHUNK),
+            };
+            let result = exposition
+                .encode_into(format, encoding, chunks)
+                .and_then(|mut chunks| chunks.flush());
+            match result {
+                Ok(()) => {}
+                // The scrape went away
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+                Err(e) => {
+                    warn!("Could not encode metrics: {}", e);
+                    let _ = sender.blocking_send(Err(e));
+                }
+            }
+        });
+        Body::wrap_stream(futures::stream::unfold(
+            receiver,
+            |mut receiver| async move { receiver.recv().await.map(|chunk| (chunk, receiver)) },
+        ))
+    }
+
+    /// Encode the metrics on a blocking thread
+    async fn encode_blocking(&self, format: Format, encoding: Encoding) -> Bytes {
+        let exposition = self.clone();
+        match tokio::task::spawn_blocking(move || exposition.encode(format, encoding)).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Could not encode metrics: {}", e);
+                Bytes::new()
+            }
+        }
+    }
+
+    /// Gather and encode the metrics
+    fn encode(&self, format: Format, encoding: Encoding) -> Bytes {
+        match self.encode_into(format, encoding, Vec::new()) {
+            Ok(buffer) => Bytes::from(buffer),
+            Err(e) => {
+                warn!("Could not encode metrics: {}", e);
+                Bytes::new()
+            }
+        }
+    }
+
+    /// Gather the metrics and encode them into a writer
+    fn encode_into<W: Write>(
+        &self,
+        format: Format,
+        encoding: Encoding,
+        writer: W,
+    ) -> io::Result<W> {
+        let mut families = self.metrics.registry().gather();
+        families.extend(prometheus::gather());
+
+        match encoding {
+            Encoding::Identity => self.write(format, &families, writer),
+            Encoding::Gzip => self
+                .write(
+                    format,
+                    &families,
+                    GzEncoder::new(writer, flate2::Compression::fast()),
+                )
+                .and_then(|gzip| gzip.finish()),
+            Encoding::Zstd => zstd::Encoder::new(writer, 0)
+                .and_then(|zstd| self.write(format, &families, zstd))
+                .and_then(|zstd| zstd.finish()),
+        }
+    }
+
+    fn write<W: Write>(
+        &self,
+        format: Format,
+        families: &[MetricFamily],
+        mut writer: W,
+    ) -> io::Result<W> {
+        match format {
+            Format::Text => TextEncoder::new()
+                .encode(families, &mut writer)
+                .map_err(io::Error::other)?,
+            Format::OpenMetrics => {
+                openmetrics::encode(families, self.metrics.exemplars(), &mut writer)?
+            }
+        }
+        Ok(writer)
+    }
+}
+
+/// Writes the encoded metrics to a streamed body in chunks of [CHUNK]
+struct Chunks {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl Chunks {
+    /// Send the buffered bytes, failing if the body has been dropped
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK));
+        self.sender
+            .blocking_send(Ok(Bytes::from(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "metrics response closed"))
+    }
+}
+
+impl Write for Chunks {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn encoding_negotiation() {
+        //! Test that the encoding follows the quality values of Accept-Encoding
+        assert_eq!(Encoding::negotiate(None), Encoding::Identity);
+        assert_eq!(Encoding::negotiate(Some("gzip, deflate")), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate(Some("gzip, zstd")), Encoding::Zstd);
+        assert_eq!(
+            Encoding::negotiate(Some("zstd;q=0.5, gzip")),
+            Encoding::Gzip
+        );
+        assert_eq!(
+            Encoding::negotiate(Some("gzip;q=0, br")),
+            Encoding::Identity
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/openmetrics-text")),
+            Format::OpenMetrics
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_and_cached() {
+        //! Test that compressed responses decode to the text format and are cached for the window
+        let metrics = Metrics::new("exposition", "test");
+        let jobs = metrics.counter("jobs", "Jobs run").unwrap();
+        jobs.inc();
+        let plain = Exposition::with_cache(&metrics, Duration::ZERO)
+            .render(Format::Text, Encoding::Identity)
+            .await;
+        let exposition = Exposition::with_cache(&metrics, Duration::from_secs(3600));
+
+        let mut gzip = String::new();
+        flate2::read::GzDecoder::new(&exposition.render(Format::Text, Encoding::Gzip).await[..])
+            .read_to_string(&mut gzip)
+            .unwrap();
+        let zstd =
+            zstd::decode_all(&exposition.render(Format::Text, Encoding::Zstd).await[..]).unwrap();
+        assert!(gzip.contains("# TYPE jobs counter"));
+        assert!(String::from_utf8(zstd)
+            .unwrap()
+            .contains("# TYPE jobs counter"));
+        assert!(std::str::from_utf8(&plain)
+            .unwrap()
+            .contains("# TYPE jobs counter"));
+
+        let cached = exposition.render(Format::Text, Encoding::Identity).await;
+        jobs.inc();
+        assert_eq!(
+            cached,
+            exposition.render(Format::Text, Encoding::Identity).await
+        );
+        assert_ne!(
+            cached,
+            Exposition::with_cache(&metrics, Duration::ZERO)
+                .render(Format::Text, Encoding::Identity)
+                .await
+        );
+    }
+
+    /// Counts how often it is gathered
+    #[derive(Clone)]
+    struct Gathers(Arc<std::sync::atomic::AtomicUsize>, prometheus::core::Desc);
+
+    impl prometheus::core::Collector for Gathers {
+        fn desc(&self) -> Vec<&prometheus::core::Desc> {
+            vec![&self.1]
+        }
+
+        fn collect(&self) -> Vec<MetricFamily> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(20));
+            Vec::new()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn streamed_and_single_flight() {
+        //! Test that uncached bodies are streamed in chunks and concurrent scrapes share one encoding
+        let metrics = Metrics::new("exposition", "test");
+        let requests = metrics
+            .counter_vec("requests", "Requests by customer", &["customer"])
+            .unwrap();
+        for i in 0..5000 {
+            requests
+                .with_label_values(&[&format!("customer{}", i)])
+                .inc();
+        }
+        let exposition = Exposition::with_cache(&metrics, Duration::ZERO);
+        let rendered = exposition.render(Format::Text, Encoding::Identity).await;
+        assert!(rendered.len() > CHUNK);
+
+        let mut body = exposition.body(Format::Text, Encoding::Identity).await;
+        let mut chunks = Vec::new();
+        while let Some(chunk) = warp::hyper::body::HttpBody::data(&mut body).await {
+            chunks.push(chunk.unwrap());
+        }
+        assert!(chunks.len() > 1);
+        // The default registry changes between gathers, so compare the series of the service
+        let requests = |body: &[u8]| -> Vec<String> {
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .lines()
+                .filter(|line| line.starts_with("requests"))
+                .map(String::from)
+                .collect()
+        };
+        assert_eq!(requests(&chunks.concat()), requests(&rendered));
+        assert_eq!(requests(&rendered).len(), 5000);
+
+        let gathers = Gathers(
+            Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+            prometheus::core::Desc::new(
+                String::from("gathers"),
+                String::from("Gathers"),
+                Vec::new(),
+                HashMap::new(),
+            )
+            .unwrap(),
+        );
+        metrics.register(&gathers);
+        let cached = Exposition::with_cache(&metrics, Duration::from_secs(3600));
+        let scrapes = (0..8).map(|_| {
+            let cached = cached.clone();
+            tokio::spawn(async move { cached.render(Format::Text, Encoding::Gzip).await })
+        });
+        let bodies = futures::future::join_all(scrapes).await;
+        assert!(bodies
+            .windows(2)
+            .all(|pair| pair[0].as_ref().unwrap() == pair[1].as_ref().unwrap()));
+        assert_eq!(gathers.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+}
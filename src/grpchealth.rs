@@ -0,0 +1,214 @@
+//! gRPC health checking protocol server
+//!
+//! Serves `grpc.health.v1.Health` so that gRPC clients and mesh sidecars can probe the service.
+//! Service names map onto the existing [HealthCheck]s by their name (ie `liveness` and `readyness`).
+//! The empty service name reports the overall health of the server, which is serving only when all [HealthCheck]s are happy.
+//! Watch streams are driven by the state changes published by [health_monitor](crate::k8slifecycle::health_monitor).
+
+use crate::k8slifecycle::HealthCheck;
+use crate::{spawn_component, HandleChannel};
+use futures::future;
+use std::pin::Pin;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_server::{Health, HealthServer};
+use tonic_health::pb::{HealthCheckRequest, HealthCheckResponse};
+use tracing::{info, warn};
+
+/// Implementation of `grpc.health.v1.Health` backed by [HealthCheck]s
+#[derive(Clone)]
+struct GrpcHealth {
+    checks: Vec<HealthCheck>,
+}
+
+impl GrpcHealth {
+    /// Find the [HealthCheck]s matching the requested service name
+    ///
+    /// The empty service name matches all [HealthCheck]s. Unknown names return None.
+    fn lookup(&self, service: &str) -> Option<Vec<HealthCheck>> {
+        if service.is_empty() {
+            return Some(self.checks.clone());
+        }
+        let found: Vec<HealthCheck> = self
+            .checks
+            .iter()
+            .filter(|hc| hc.name() == service)
+            .cloned()
+            .collect();
+        if found.is_empty() {
+            None
+        } else {
+            Some(found)
+        }
+    }
+}
+
+/// Summarise a set of [HealthCheck]s into a single gRPC [ServingStatus]
+fn serving_status(checks: &[HealthCheck]) -> ServingStatus {
+    if checks.iter().all(|hc| hc.status().0) {
+        ServingStatus::Serving
+    } else {
+        ServingStatus::NotServing
+    }
+}
+
+#[tonic::async_trait]
+impl Health for GrpcHealth {
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.get_ref().service.as_str();
+        match self.lookup(service) {
+            None => Err(Status::not_found("service not registered")),
+            Some(checks) => Ok(Response::new(HealthCheckResponse {
+                status: serving_status(&checks) as i32,
+            })),
+        }
+    }
+
+    type WatchStream =
+        Pin<Box<dyn Stream<Item = Result<HealthCheckResponse, Status>> + Send + 'static>>;
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let checks = self.lookup(request.get_ref().service.as_str());
+        let (tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            let checks = match checks {
+                Some(checks) => checks,
+                None => {
+                    // Unknown services are reported but the call is held open as per the protocol
+                    let status = ServingStatus::ServiceUnknown as i32;
+                    if tx.send(Ok(HealthCheckResponse { status })).await.is_ok() {
+                        tx.closed().await;
+                    }
+                    return;
+                }
+            };
+
+            let mut watches: Vec<_> = checks.iter().map(|hc| hc.watch()).collect();
+            let mut last = None;
+            loop {
+                let status = serving_status(&checks);
+                if last != Some(status) {
+                    last = Some(status);
+                    let response = HealthCheckResponse {
+                        status: status as i32,
+                    };
+                    if tx.send(Ok(response)).await.is_err() {
+                        break;
+                    }
+                }
+                let changed = future::select_all(watches.iter_mut().map(|w| Box::pin(w.changed())));
+                tokio::select! {
+                    (result, _, _) = changed => if result.is_err() { break },
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+
+        Ok(Response::new(
+            Box::pin(ReceiverStream::new(rx)) as Self::WatchStream
+        ))
+    }
+}
+
+/// Start the gRPC health server as a [HandleChannel] to be managed by the [UService](crate::UService)
+pub async fn grpc_health_listen(
+    port: u16,
+    liveness: &HealthCheck,
+    readyness: &HealthCheck,
+) -> HandleChannel {
+    info!("Starting grpc health on {}", port);
+
+    let service = GrpcHealth {
+        checks: vec![liveness.clone(), readyness.clone()],
+    };
+
+    let (channel, mut rx) = mpsc::channel(1);
+
+    let server = tonic::transport::Server::builder()
+        .add_service(HealthServer::new(service))
+        .serve_with_shutdown(([0, 0, 0, 0], port).into(), async move {
+            rx.recv().await;
+        });
+
+    let handle = spawn_component("grpc_health", async move {
+        if let Err(e) = server.await {
+            warn!("grpc health server failed: {}", e);
+        }
+    });
+
+    HandleChannel { handle, channel }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::k8slifecycle::{health_monitor, HealthProbe};
+    use std::time::Duration;
+    use tokio::time::sleep;
+    use tokio_stream::StreamExt;
+    use tonic::transport::Channel;
+    use tonic::Code;
+    use tonic_health::pb::health_client::HealthClient;
+
+    fn request(service: &str) -> HealthCheckRequest {
+        HealthCheckRequest {
+            service: service.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn grpc_check_and_watch() {
+        //! Test that Check maps service names onto HealthChecks and Watch streams changes
+        let liveness = HealthCheck::new("liveness");
+        let readyness = HealthCheck::new("readyness");
+        let mut alive = HealthProbe::new("alive", Duration::from_secs(60));
+        let ready = HealthProbe::new("ready", Duration::from_millis(1500));
+        liveness.add(&alive);
+        readyness.add(&ready);
+        alive.tick();
+
+        let monitor = health_monitor(Duration::from_millis(100), &[&liveness, &readyness]).await;
+        let server = grpc_health_listen(7981, &liveness, &readyness).await;
+        sleep(Duration::from_millis(200)).await;
+
+        let channel = Channel::from_static("http://127.0.0.1:7981")
+            .connect()
+            .await
+            .expect("Connect to grpc health");
+        let mut client = HealthClient::new(channel);
+
+        let resp = client.check(request("liveness")).await.unwrap();
+        assert_eq!(resp.into_inner().status, ServingStatus::Serving as i32);
+        let resp = client.check(request("")).await.unwrap();
+        assert_eq!(resp.into_inner().status, ServingStatus::Serving as i32);
+        let resp = client.check(request("unknown")).await;
+        assert_eq!(resp.unwrap_err().code(), Code::NotFound);
+
+        let mut stream = client
+            .watch(request("readyness"))
+            .await
+            .unwrap()
+            .into_inner();
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.status, ServingStatus::Serving as i32);
+        let second = stream.next().await.unwrap().unwrap();
+        assert_eq!(second.status, ServingStatus::NotServing as i32);
+        drop(stream);
+        drop(client);
+
+        server.channel.send(()).await.unwrap();
+        server.handle.await.unwrap();
+        monitor.channel.send(()).await.unwrap();
+        monitor.handle.await.unwrap();
+    }
+}
@@ -0,0 +1,516 @@
+//! Request metrics for warp http services
+//!
+//! Wrap any warp filter with [with_metrics] to record request durations, in flight requests and request and response sizes.
+//! Names and labels follow the OpenTelemetry HTTP semantic conventions as exported to Prometheus.
+//!
+//! Requests are labelled by route template rather than the raw path to keep the number of series bounded.
+//! The templates are given to [HttpMetrics] and a path that matches none of them is labelled `other`.
+//! Requests the wrapped filter rejects are answered by [recover] and recorded with the status of their error response.
+//!
+//! The buckets of the duration histogram are configured by [Buckets](crate::metrics::Buckets) and with the `hdr` feature
+//! durations can also be recorded in [Percentiles] by route template for exact percentiles, exported as a summary.
+//!
+//! Requests carrying a W3C `traceparent` header, or traced with the `trace` feature, record their trace id as an [exemplar](crate::openmetrics::Exemplar) of the duration bucket they land in.
+
+use crate::cardinality::{CardinalityLimits, Guarded, HTTP_LIMIT};
+use crate::logging;
+use crate::openmetrics::Exemplars;
+#[cfg(feature = "hdr")]
+use crate::percentiles::Percentiles;
+use prometheus::{exponential_buckets, HistogramOpts, HistogramVec, IntGaugeVec, Opts, Registry};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::error;
+use warp::http::{HeaderMap, Method, StatusCode};
+use warp::hyper::body::HttpBody;
+use warp::path::FullPath;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// Labels of the per request metrics
+const REQUEST_LABELS: &[&str] = &[
+    "env",
+    "server",
+    "http_request_method",
+    "http_route",
+    "http_response_status_code",
+];
+
+/// The collectors recording http requests
+///
+/// Created once per [Metrics](crate::metrics::Metrics) and shared by each of its http servers.
+#[derive(Clone)]
+pub struct HttpCollectors {
+    /// `http_server_request_duration_seconds`
+    pub duration: Guarded<HistogramVec>,
+    /// `http_server_active_requests`
+    pub active: Guarded<IntGaugeVec>,
+    /// `http_server_request_body_size_bytes`
+    pub request_size: Guarded<HistogramVec>,
+    /// `http_server_response_body_size_bytes`
+    pub response_size: Guarded<HistogramVec>,
+    /// Exemplars of `http_server_request_duration_seconds`
+    pub exemplars: Exemplars,
+    /// Upper bounds of the buckets of `http_server_request_duration_seconds`
+    pub duration_buckets: Arc<Vec<f64>>,
+    /// High resolution request durations by route template, when enabled
+    #[cfg(feature = "hdr")]
+    pub percentiles: Option<Percentiles>,
+}
+
+impl HttpCollectors {
+    /// Create the collectors with the given duration buckets and register them, guarded by the [CardinalityLimits]
+    pub fn register(
+        registry: &Registry,
+        buckets: &[f64],
+        limits: &CardinalityLimits,
+    ) -> prometheus::Result<HttpCollectors> {
+        let collectors = HttpCollectors {
+            duration: limits.guard(
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "http_server_request_duration_seconds",
+                        "Duration of HTTP server requests",
+                    )
+                    .buckets(buckets.to_vec()),
+                    REQUEST_LABELS,
+                )?,
+                "http_server_request_duration_seconds",
+                Some(HTTP_LIMIT),
+            ),
+            active: limits.guard(
+                IntGaugeVec::new(
+                    Opts::new(
+                        "http_server_active_requests",
+                        "Number of active HTTP server requests",
+                    ),
+                    &["env", "server", "http_request_method"],
+                )?,
+                "http_server_active_requests",
+                Some(HTTP_LIMIT),
+            ),
+            request_size: limits.guard(
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "http_server_request_body_size_bytes",
+                        "Size of HTTP server request bodies",
+                    )
+                    .buckets(size_buckets()),
+                    REQUEST_LABELS,
+                )?,
+                "http_server_request_body_size_bytes",
+                Some(HTTP_LIMIT),
+            ),
+            response_size: limits.guard(
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "http_server_response_body_size_bytes",
+                        "Size of HTTP server response bodies",
+                    )
+                    .buckets(size_buckets()),
+                    REQUEST_LABELS,
+                )?,
+                "http_server_response_body_size_bytes",
+                Some(HTTP_LIMIT),
+            ),
+            exemplars: Exemplars::default(),
+            duration_buckets: Arc::new(buckets.to_vec()),
+            #[cfg(feature = "hdr")]
+            percentiles: None,
+        };
+
+        registry.register(Box::new(collectors.duration.clone()))?;
+        registry.register(Box::new(collectors.active.clone()))?;
+        registry.register(Box::new(collectors.request_size.clone()))?;
+        registry.register(Box::new(collectors.response_size.clone()))?;
+        Ok(collectors)
+    }
+}
+
+/// Buckets for body sizes from 64B to 16MB
+fn size_buckets() -> Vec<f64> {
+    exponential_buckets(64.0, 4.0, 10).expect("valid buckets")
+}
+
+/// Collectors and labels for the metrics of a http server
+#[derive(Clone)]
+pub struct HttpMetrics {
+    collectors: HttpCollectors,
+    env: String,
+    server: &'static str,
+    routes: Arc<Vec<String>>,
+}
+
+impl HttpMetrics {
+    /// Create [HttpMetrics] for the named server
+    ///
+    /// Route templates are paths where a `{name}` segment matches any single segment eg `/sample/{id}`
+    pub fn new<S: AsRef<str>>(
+        collectors: &HttpCollectors,
+        env: &str,
+        server: &'static str,
+        routes: &[S],
+    ) -> HttpMetrics {
+        HttpMetrics {
+            collectors: collectors.clone(),
+            env: env.to_string(),
+            server,
+            routes: Arc::new(
+                routes
+                    .iter()
+                    .map(|route| route.as_ref().to_string())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Find the route template matching a path
+    pub fn route(&self, path: &str) -> &str {
+        self.routes
+            .iter()
+            .find(|route| route_matches(route, path))
+            .map(|route| route.as_str())
+            .unwrap_or("other")
+    }
+}
+
+/// Check if a path matches a route template
+fn route_matches(route: &str, path: &str) -> bool {
+    let mut route = route.split('/').filter(|s| !s.is_empty());
+    let mut path = path.split('/').filter(|s| !s.is_empty());
+    loop {
+        match (route.next(), path.next()) {
+            (None, None) => return true,
+            (Some(r), Some(p)) => {
+                if !(r == p || (r.starts_with('{') && r.ends_with('}'))) {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Reduce the method to the set of known methods as per the OpenTelemetry conventions
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::CONNECT => "CONNECT",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::PATCH => "PATCH",
+        _ => "_OTHER",
+    }
+}
+
+/// Status of the error response for a rejection, as warp would reply with
+pub fn rejection_status(rejection: &Rejection) -> StatusCode {
+    use warp::reject::*;
+
+    if rejection.is_not_found() {
+        StatusCode::NOT_FOUND
+    } else if rejection.find::<MethodNotAllowed>().is_some() {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else if rejection.find::<InvalidHeader>().is_some()
+        || rejection.find::<MissingHeader>().is_some()
+        || rejection.find::<MissingCookie>().is_some()
+        || rejection.find::<InvalidQuery>().is_some()
+        || rejection
+            .find::<warp::body::BodyDeserializeError>()
+            .is_some()
+    {
+        StatusCode::BAD_REQUEST
+    } else if rejection.find::<LengthRequired>().is_some() {
+        StatusCode::LENGTH_REQUIRED
+    } else if rejection.find::<PayloadTooLarge>().is_some() {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else if rejection.find::<UnsupportedMediaType>().is_some() {
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    } else if rejection.find::<warp::cors::CorsForbidden>().is_some() {
+        StatusCode::FORBIDDEN
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Answer a rejection with its error response, for use with [Filter::recover]
+///
+/// Unhandled custom rejections are logged and answered with a 500.
+pub async fn recover(rejection: Rejection) -> Result<Response, Infallible> {
+    let status = rejection_status(&rejection);
+    if status == StatusCode::INTERNAL_SERVER_ERROR {
+        error!("Unhandled rejection: {:?}", rejection);
+    }
+    let reason = if status == StatusCode::NOT_FOUND {
+        ""
+    } else {
+        status.canonical_reason().unwrap_or_default()
+    };
+    Ok(warp::reply::with_status(reason, status).into_response())
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(warp::http::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Tracks a request while it is in flight
+///
+/// The active request gauge is decremented when dropped.
+struct Tracker {
+    metrics: HttpMetrics,
+    method: &'static str,
+    path: FullPath,
+    request_size: Option<u64>,
+    trace_id: Option<String>,
+    start: Instant,
+}
+
+impl Tracker {
+    fn start(metrics: HttpMetrics, method: Method, path: FullPath, headers: HeaderMap) -> Tracker {
+        let method = method_label(&method);
+        metrics
+            .collectors
+            .active
+            .with_label_values(&[&metrics.env, metrics.server, method])
+            .inc();
+        Tracker {
+            request_size: content_length(&headers),
+            trace_id: logging::trace_id(&headers),
+            metrics,
+            method,
+            path,
+            start: Instant::now(),
+        }
+    }
+
+    fn finish(&self, response: &Response) {
+        let status = response.status();
+        let labels = [
+            self.metrics.env.as_str(),
+            self.metrics.server,
+            self.method,
+            self.metrics.route(self.path.as_str()),
+            status.as_str(),
+        ];
+        // Record the route on the request span of logging::serve
+        let span = tracing::Span::current();
+        span.record("route", labels[3]);
+        #[cfg(feature = "trace")]
+        span.record("otel.name", format!("{} {}", self.method, labels[3]));
+
+        let collectors = &self.metrics.collectors;
+        let elapsed = self.start.elapsed();
+        let duration = elapsed.as_secs_f64();
+        #[cfg(feature = "hdr")]
+        if let Some(percentiles) = &collectors.percentiles {
+            percentiles.record(labels[3], elapsed);
+        }
+        collectors
+            .duration
+            .with_label_values(&labels)
+            .observe(duration);
+        // Series folded by the cardinality guard keep no exemplars
+        if let Some(trace_id) = self
+            .trace_id
+            .as_ref()
+            .filter(|_| collectors.duration.admits(&labels))
+        {
+            let labels: Vec<_> = REQUEST_LABELS.iter().copied().zip(labels).collect();
+            collectors.exemplars.record(
+                "http_server_request_duration_seconds",
+                &labels,
+                &collectors.duration_buckets,
+                duration,
+                trace_id,
+            );
+        }
+        if let Some(size) = self.request_size {
+            collectors
+                .request_size
+                .with_label_values(&labels)
+                .observe(size as f64);
+        }
+        if let Some(size) = response
+            .body()
+            .size_hint()
+            .exact()
+            .or_else(|| content_length(response.headers()))
+        {
+            collectors
+                .response_size
+                .with_label_values(&labels)
+                .observe(size as f64);
+        }
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.metrics
+            .collectors
+            .active
+            .with_label_values(&[&self.metrics.env, self.metrics.server, self.method])
+            .dec();
+    }
+}
+
+/// Wrap a filter so that every request it handles is recorded in the http metrics
+///
+/// Rejections of the filter are answered by [recover] and recorded with their status, so the wrapped filter never rejects
+/// and should hold all the routes of a server.
+pub fn with_metrics<F, T>(
+    filter: F,
+    metrics: HttpMetrics,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (T,), Error = Rejection> + Clone + Send + Sync + 'static,
+    T: Reply,
+{
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .map(move |method, path, headers| Tracker::start(metrics.clone(), method, path, headers))
+        .and(filter.map(Reply::into_response).recover(recover).unify())
+        .and_then(|tracker: Tracker, response: Response| async move {
+            tracker.finish(&response);
+            Ok::<_, Rejection>(response)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_collectors(registry: &Registry) -> HttpCollectors {
+        let limits = CardinalityLimits::register(registry, &Default::default()).unwrap();
+        HttpCollectors::register(registry, prometheus::DEFAULT_BUCKETS, &limits).unwrap()
+    }
+
+    #[test]
+    fn route_templates() {
+        //! Test that paths are reduced to their route template
+        let collectors = test_collectors(&Registry::new());
+        let metrics = HttpMetrics::new(
+            &collectors,
+            "test",
+            "test",
+            &["/sample/sample1", "/sample/{id}/detail"],
+        );
+        assert_eq!(metrics.route("/sample/sample1"), "/sample/sample1");
+        assert_eq!(metrics.route("/sample/42/detail"), "/sample/{id}/detail");
+        assert_eq!(metrics.route("/sample/42"), "other");
+        assert_eq!(metrics.route("/sample/sample1/extra"), "other");
+    }
+
+    #[tokio::test]
+    async fn metrics_recorded() {
+        //! Test that a wrapped filter records durations, sizes and active requests
+        let route = warp::post()
+            .and(warp::path!("hello" / String))
+            .map(|_name| warp::reply::with_status("Hello", StatusCode::CREATED));
+        let collectors = test_collectors(&Registry::new());
+        let metrics = HttpMetrics::new(&collectors, "metrics-test", "test", &["/hello/{name}"]);
+        let wrapped = with_metrics(route, metrics);
+
+        let labels = ["metrics-test", "test", "POST", "/hello/{name}", "201"];
+        let durations = collectors.duration.with_label_values(&labels);
+        let active = collectors
+            .active
+            .with_label_values(&["metrics-test", "test", "POST"]);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/hello/world")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body("request")
+            .reply(&wrapped)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/missing")
+            .reply(&wrapped)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/hello/world")
+            .reply(&wrapped)
+            .await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        assert_eq!(durations.get_sample_count(), 1);
+        assert_eq!(active.get(), 0);
+        let request_size = collectors.request_size.with_label_values(&labels);
+        assert_eq!(request_size.get_sample_sum(), 7.0);
+        let response_size = collectors.response_size.with_label_values(&labels);
+        assert_eq!(response_size.get_sample_sum(), 5.0);
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(collectors.duration.clone()))
+            .unwrap();
+        let mut buffer = Vec::new();
+        crate::openmetrics::encode(&registry.gather(), &collectors.exemplars, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains("# {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"}"));
+        assert!(text.contains(
+            "_count{env=\"metrics-test\",http_request_method=\"POST\",http_response_status_code=\"404\",http_route=\"other\",server=\"test\"} 1"
+        ));
+        assert!(text.contains("http_response_status_code=\"405\",http_route=\"/hello/{name}\""));
+    }
+
+    #[cfg(feature = "hdr")]
+    #[tokio::test]
+    async fn percentiles_exported() {
+        //! Test that request durations are recorded in the percentiles and exported as quantiles by route
+        let config = crate::metrics::MetricsConfig {
+            latency_percentiles: true,
+            ..Default::default()
+        };
+        let metrics = crate::metrics::Metrics::with_config("test", "test", &config);
+        let route = warp::path!("slow").and_then(|| async {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            Ok::<_, Rejection>("done")
+        });
+        let wrapped = with_metrics(route, metrics.http("test", &["/slow"]));
+        for _ in 0..3 {
+            let resp = warp::test::request().path("/slow").reply(&wrapped).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let families = metrics.registry().gather();
+        let family = families
+            .iter()
+            .find(|f| f.get_name() == "http_server_request_duration_quantiles_seconds")
+            .expect("quantiles exported");
+        let metric = &family.get_metric()[0];
+        assert!(metric
+            .get_label()
+            .iter()
+            .any(|l| l.get_name() == "http_route" && l.get_value() == "/slow"));
+        let summary = metric.get_summary();
+        assert_eq!(summary.get_sample_count(), 3);
+        let quantiles: Vec<_> = summary
+            .get_quantile()
+            .iter()
+            .map(|q| q.get_quantile())
+            .collect();
+        assert_eq!(quantiles, crate::percentiles::QUANTILES);
+        let p50 = summary.get_quantile()[0].get_value();
+        assert!((0.02..0.5).contains(&p50), "p50 of {}", p50);
+    }
+}
@@ -1,46 +1,32 @@
 //! supporting functions for a microservice
 
-use crate::HandleChannel;
-use atomic::Atomic;
+use crate::accesslog::{with_access_log, AccessLog};
+use crate::buildinfo::ServiceInfo;
+use crate::httpmetrics::with_metrics;
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::{spawn_component, HandleChannel};
+use arc_swap::ArcSwap;
 use lazy_static::lazy_static;
-use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry};
+use serde::ser::{Serialize, SerializeMap, Serializer};
 use std::collections::HashMap;
-use std::sync::atomic::Ordering;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use tokio::sync::mpsc;
-use warp::Filter;
-use log::{info};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::time::sleep;
+use tracing::{info, warn};
 
 
 lazy_static! {
-    pub static ref INCOMING_REQUESTS: IntCounter =
-        IntCounter::new("incoming_requests", "Incoming Requests").expect("metric can be created");
-    pub static ref RESPONSE_CODE_COLLECTOR: IntCounterVec = IntCounterVec::new(
-        Opts::new("response_code", "Response Codes"),
-        &["env", "statuscode", "type"]
-    )
-    .expect("metric can be created");
-    pub static ref RESPONSE_TIME_COLLECTOR: HistogramVec = HistogramVec::new(
-        HistogramOpts::new("response_time", "Response Times"),
-        &["env"]
-    )
-    .expect("metric can be created");
-    pub static ref REGISTRY: Registry = Registry::new();
+    /// Reference point for [HealthProbe] times so they can be held in an [AtomicU64]
+    static ref EPOCH: Instant = Instant::now();
 }
 
-fn register_custom_metrics() {
-    REGISTRY
-        .register(Box::new(INCOMING_REQUESTS.clone()))
-        .expect("collector can be registered");
-
-    REGISTRY
-        .register(Box::new(RESPONSE_CODE_COLLECTOR.clone()))
-        .expect("collector can be registered");
-
-    REGISTRY
-        .register(Box::new(RESPONSE_TIME_COLLECTOR.clone()))
-        .expect("collector can be registered");
+/// Nanoseconds elapsed since [EPOCH]
+fn epoch_nanos() -> u64 {
+    EPOCH.elapsed().as_nanos() as u64
 }
 
 /// A structure to create kubernetes [HealthProbe]s
@@ -53,28 +39,33 @@ pub struct HealthProbe {
     name: String,
     /// Time by which the [HealthProbe] can remain un[tick](HealthProbe::tick)ed before it reports failed
     margin: Duration,
-    /// Time of last checkin
-    time: Arc<Atomic<Instant>>,
+    /// Time of last checkin as nanoseconds since [EPOCH]
+    time: Arc<AtomicU64>,
 }
 impl HealthProbe {
     pub fn new(name: &str, margin: Duration) -> HealthProbe {
         HealthProbe {
             name: name.to_string(),
             margin,
-            time: Arc::new(Atomic::new(Instant::now())),
+            time: Arc::new(AtomicU64::new(epoch_nanos())),
         }
     }
 
     /// Trigger an update of the [HealthProbe] keeping it wthin the time [HealthProbe::margin]
     pub fn tick(&mut self) {
-        self.time.store(Instant::now(), Ordering::SeqCst);
+        self.time.store(epoch_nanos(), Ordering::SeqCst);
     }
 
     /// Check and reply if the probe is valid
     ///
     /// Valid means the probe has been [ticked](HealthCheck::tick) within the [HealthProbe::margin]
-    fn valid(&self) -> bool {
-        self.time.load(Ordering::SeqCst).elapsed() <= self.margin
+    pub fn valid(&self) -> bool {
+        self.valid_at(epoch_nanos())
+    }
+
+    /// Check if the probe is valid at a point in time given as nanoseconds since [EPOCH]
+    fn valid_at(&self, now: u64) -> bool {
+        Duration::from_nanos(now.saturating_sub(self.time.load(Ordering::SeqCst))) <= self.margin
     }
 }
 impl Clone for HealthProbe {
@@ -88,6 +79,88 @@ impl Clone for HealthProbe {
 }
 
 
+/// Snapshot of the [HealthProbe]s in a [HealthCheck] evaluated at a single point in time
+///
+/// Creating a [HealthStatus] does not allocate, the validity of each [HealthProbe] is calculated as it is read.
+/// It serializes to a json map of [HealthProbe] name to validity.
+#[derive(Clone)]
+pub struct HealthStatus {
+    probes: Arc<Vec<HealthProbe>>,
+    now: u64,
+}
+
+impl HealthStatus {
+    /// True if all the [HealthProbe]s are valid
+    pub fn happy(&self) -> bool {
+        self.probes.iter().all(|probe| probe.valid_at(self.now))
+    }
+
+    /// Number of [HealthProbe]s
+    pub fn len(&self) -> usize {
+        self.probes.len()
+    }
+
+    /// True if there are no [HealthProbe]s
+    pub fn is_empty(&self) -> bool {
+        self.probes.is_empty()
+    }
+
+    /// Validity of the named [HealthProbe]
+    pub fn get(&self, name: &str) -> Option<bool> {
+        self.probes
+            .iter()
+            .find(|probe| probe.name == name)
+            .map(|probe| probe.valid_at(self.now))
+    }
+
+    /// Iterate over the name and validity of each [HealthProbe]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, bool)> {
+        let now = self.now;
+        self.probes
+            .iter()
+            .map(move |probe| (probe.name.as_str(), probe.valid_at(now)))
+    }
+}
+
+impl fmt::Debug for HealthStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl Serialize for HealthStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (name, valid) in self.iter() {
+            map.serialize_entry(name, &valid)?;
+        }
+        map.end()
+    }
+}
+
+/// A change of state within a [HealthCheck]
+///
+/// Emitted via [HealthCheck::subscribe] whenever the overall state of the [HealthCheck] or the state of one of its [HealthProbe]s changes.
+#[derive(Clone, Debug)]
+pub struct HealthEvent {
+    /// Name of the [HealthCheck] that changed
+    pub check: String,
+    /// Name of the [HealthProbe] that changed or None if this is the overall state of the [HealthCheck]
+    pub probe: Option<String>,
+    /// State before the change
+    pub previous: bool,
+    /// State after the change
+    pub current: bool,
+    /// Time the change was detected
+    pub time: SystemTime,
+}
+
+/// Last evaluated state of a [HealthCheck] used to detect transitions
+struct HealthState {
+    happy: bool,
+    probes: HashMap<String, bool>,
+}
+
 /// A structure to create kubernetes health checks.
 ///
 /// [HealthProbe]s can be added to it and these then must be updated at regular intervals or will result in failing the [HealthCheck].
@@ -97,12 +170,20 @@ impl Clone for HealthProbe {
 ///
 /// During operation the [HealthProbe] is updated by the service. The service does not need any direct relationship with the [HealthCheck]
 ///
+/// The [HealthProbe]s are held copy-on-write so that [HealthCheck::status] is lock free.
+///
 #[derive(Clone)]
 pub struct HealthCheck {
     /// A name for the [HealthCheck]
     name: String,
     /// an internal list of the [HealthProbe]s attached to the [HealthCheck]
-    probe_list: Arc<Mutex<Vec<HealthProbe>>>,
+    probe_list: Arc<ArcSwap<Vec<HealthProbe>>>,
+    /// state as of the last [HealthCheck::evaluate]
+    state: Arc<Mutex<HealthState>>,
+    /// channel for [HealthEvent]s
+    events: broadcast::Sender<HealthEvent>,
+    /// channel for the overall state
+    happy: Arc<watch::Sender<bool>>,
 }
 
 impl HealthCheck {
@@ -111,62 +192,154 @@ impl HealthCheck {
     pub fn new(name: &str) -> HealthCheck {
         info!("Creating HealthCheck: {}", name);
 
+        let (events, _) = broadcast::channel(64);
+        let (happy, _) = watch::channel(true);
+
         HealthCheck {
             name: name.to_string(),
-            probe_list: Arc::new(Mutex::new(Vec::new())),
+            probe_list: Arc::new(ArcSwap::from_pointee(Vec::new())),
+            state: Arc::new(Mutex::new(HealthState {
+                happy: true,
+                probes: HashMap::new(),
+            })),
+            events,
+            happy: Arc::new(happy),
         }
     }
 
+    /// Name of the [HealthCheck]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     /// Add [HealthProbe] to [HealthCheck]
     pub fn add(&self, probe: &HealthProbe) {
-        self.probe_list.lock().unwrap().push(probe.clone());
+        self.probe_list.rcu(|probes| {
+            let mut probes = Vec::clone(probes);
+            probes.push(probe.clone());
+            probes
+        });
     }
 
     /// get status which is a json'able object providing detail info on [HealthProbe] and a bool to summarise
-    pub fn status(&self) -> (bool, HashMap<String, bool>) {
-        let mut happy = true;
+    pub fn status(&self) -> (bool, HealthStatus) {
+        let detail = HealthStatus {
+            probes: self.probe_list.load_full(),
+            now: epoch_nanos(),
+        };
+        (detail.happy(), detail)
+    }
 
-        let detail: HashMap<_, _> = self
-            .probe_list
-            .lock()
-            .unwrap()
-            .iter()
-            .map(|x| {
-                if !x.valid() {
-                    happy = false;
+    /// Receive a [HealthEvent] for every change of state detected by [HealthCheck::evaluate]
+    pub fn subscribe(&self) -> broadcast::Receiver<HealthEvent> {
+        self.events.subscribe()
+    }
+
+    /// Watch the overall state of the [HealthCheck] as updated by [HealthCheck::evaluate]
+    pub fn watch(&self) -> watch::Receiver<bool> {
+        self.happy.subscribe()
+    }
+
+    /// Compare the current status with the last evaluation, logging and publishing a [HealthEvent] for each change
+    ///
+    /// [HealthProbe]s seen for the first time are recorded without an event.
+    pub fn evaluate(&self) {
+        let (happy, detail) = self.status();
+        let time = SystemTime::now();
+        let mut state = self.state.lock().unwrap();
+
+        let mut changes = Vec::new();
+        for (name, current) in detail.iter() {
+            match state.probes.get_mut(name) {
+                Some(previous) if *previous != current => {
+                    changes.push((Some(name.to_string()), *previous, current));
+                    *previous = current;
+                }
+                Some(_) => {}
+                None => {
+                    state.probes.insert(name.to_string(), current);
                 }
-                (x.name.clone(), x.valid())
-            })
-            .collect();
-        (happy, detail)
+            }
+        }
+        if state.happy != happy {
+            changes.push((None, state.happy, happy));
+            state.happy = happy;
+            self.happy.send_replace(happy);
+        }
+        drop(state);
+
+        for (probe, previous, current) in changes {
+            let subject = match &probe {
+                Some(probe) => format!("HealthCheck {} probe {}", self.name, probe),
+                None => format!("HealthCheck {}", self.name),
+            };
+            if current {
+                info!("{} recovered", subject);
+            } else {
+                warn!("{} failed", subject);
+            }
+            // An error only means there are no subscribers
+            let _ = self.events.send(HealthEvent {
+                check: self.name.clone(),
+                probe,
+                previous,
+                current,
+                time,
+            });
+        }
     }
 }
 
+/// Periodically [evaluate](HealthCheck::evaluate) [HealthCheck]s so that subscribers receive [HealthEvent]s
+pub async fn health_monitor(interval: Duration, checks: &[&HealthCheck]) -> HandleChannel {
+    info!("Starting health monitor");
+
+    let checks: Vec<HealthCheck> = checks.iter().map(|hc| (*hc).clone()).collect();
+    let (channel, mut rx) = mpsc::channel(1);
+
+    let handle = spawn_component("health_monitor", async move {
+        loop {
+            for hc in checks.iter() {
+                hc.evaluate();
+            }
+            tokio::select! {
+                _ = sleep(interval) => {},
+                _ = rx.recv() => break,
+            }
+        }
+        info!("Health monitor closed");
+    });
+
+    HandleChannel { handle, channel }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn health_listen<'a>(
     basepath: &'static str,
     port: u16,
     liveness: &'a HealthCheck,
     readyness: &'a HealthCheck,
     channel_http_kill: tokio::sync::mpsc::Sender<()>,
+    metrics: &Metrics,
+    info: &ServiceInfo,
+    access_log: &AccessLog,
 ) -> HandleChannel {
     info!("Starting health http on {}", port);
 
-    register_custom_metrics();
-
-    let api = filters::health(basepath, liveness.clone(), readyness.clone(), channel_http_kill);
+    let api = filters::health(basepath, liveness.clone(), readyness.clone(), channel_http_kill, metrics.clone(), info.clone());
 
-    let routes = api.with(warp::log("health"));
+    let http_metrics = metrics.http("health", &filters::routes(basepath));
+    let routes = with_access_log(with_metrics(api, http_metrics), access_log.clone(), "health");
 
     info!("Starting health service");
 
     let (channel, mut rx) = mpsc::channel(1);
 
-    let (_addr, server) =
-        warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], port), async move {
-            rx.recv().await;
-        });
+    let server = logging::serve(routes, port, async move {
+        rx.recv().await;
+    });
 
-    let handle = tokio::task::spawn(server);
+    let handle = spawn_component("health_listen", server);
 
     HandleChannel { handle, channel }
 }
@@ -174,20 +347,36 @@ pub async fn health_listen<'a>(
 /// The filters through used to build up the http route for the k8s health system
 mod filters {
     use super::handlers;
+    use crate::buildinfo::ServiceInfo;
+    use crate::exposition::Exposition;
     use crate::k8slifecycle::HealthCheck;
+    use crate::logging::LogLevel;
+    use crate::metrics::Metrics;
     use warp::Filter;
 
+    /// Route templates of the health system for labelling metrics
+    pub fn routes(basepath: &str) -> Vec<String> {
+        ["alive", "ready", "kill", "metrics", "info", "loglevel"]
+            .iter()
+            .map(|route| format!("/{}/{}", basepath, route))
+            .collect()
+    }
+
     pub fn health(
         basepath: &'static str,
         liveness: HealthCheck,
         readyness: HealthCheck,
         channel_http_kill: tokio::sync::mpsc::Sender<()>,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        metrics: Metrics,
+        info: ServiceInfo,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path(basepath).and(
             liveness_check(liveness)
                 .or(readyness_check(readyness))
                 .or(kill_signal(channel_http_kill))
-                .or(prometheus_metrics()),
+                .or(prometheus_metrics(metrics))
+                .or(service_info(info))
+                .or(log_level(crate::logging::log_level())),
         )
     }
     pub fn kill_signal(
@@ -215,19 +404,61 @@ mod filters {
             .and_then(handlers::readyness)
     }
     pub fn prometheus_metrics(
+        metrics: Metrics,
     ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::get()
             .and(warp::path("metrics"))
-            // .and(with_metrics())
+            .and(with_exposition(Exposition::new(&metrics)))
+            .and(warp::header::optional::<String>("accept"))
+            .and(warp::header::optional::<String>("accept-encoding"))
             .and_then(handlers::metrics)
     }
 
+    pub fn service_info(
+        info: ServiceInfo,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(warp::path!("info"))
+            .map(move || warp::reply::json(&info))
+    }
+
+    /// Read with GET, change with PUT and reset with DELETE the log filter, if logging was initialised
+    pub fn log_level(
+        level: Option<LogLevel>,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let with_level = warp::any().and_then(move || {
+            let level = level.clone();
+            async move { level.ok_or_else(warp::reject::not_found) }
+        });
+        let get = warp::get()
+            .and(warp::path!("loglevel"))
+            .and(with_level.clone())
+            .and_then(handlers::log_level);
+        let put = warp::put()
+            .and(warp::path!("loglevel"))
+            .and(with_level.clone())
+            .and(warp::body::content_length_limit(4096))
+            .and(warp::body::json())
+            .and_then(handlers::set_log_level);
+        let delete = warp::delete()
+            .and(warp::path!("loglevel"))
+            .and(with_level)
+            .and_then(handlers::reset_log_level);
+        get.or(put).or(delete)
+    }
+
     fn with_channel(
         channel: tokio::sync::mpsc::Sender<()>,
     ) -> impl Filter<Extract = (tokio::sync::mpsc::Sender<()>,), Error = std::convert::Infallible> + Clone {
         warp::any().map(move || channel.clone())
     }
 
+    fn with_exposition(
+        exposition: Exposition,
+    ) -> impl Filter<Extract = (Exposition,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || exposition.clone())
+    }
+
     fn with_heathcheck(
         hc: HealthCheck,
     ) -> impl Filter<Extract = (HealthCheck,), Error = std::convert::Infallible> + Clone {
@@ -240,10 +471,14 @@ mod filters {
 /// All health k8s health handlers are provided here. These reply to k8s alive, ready and prometheus metrics.
 mod handlers {
     use crate::k8slifecycle::HealthCheck;
-    use crate::k8slifecycle::REGISTRY;
+    use crate::exposition::{Encoding, Exposition, Format};
+    use crate::logging::LogLevel;
+    use serde_json::{json, Value};
     use std::convert::Infallible;
+    use std::time::Duration;
+    use warp::http::header::{self, HeaderValue};
     use warp::http::StatusCode;
-    use log::{info, debug};
+    use tracing::{info, debug, warn};
 
     /// Creates a signal to close the uservice cleanly
     pub async fn kill(channel: tokio::sync::mpsc::Sender<()>) -> Result<impl warp::Reply, Infallible> {
@@ -280,39 +515,72 @@ mod handlers {
         ))
     }
 
-    /// provide [Prometheus](https://prometheus.io) metrics
-    pub async fn metrics() -> Result<impl warp::Reply, Infallible> {
-        debug!("Returning metrics");
-        use prometheus::Encoder;
-        let encoder = prometheus::TextEncoder::new();
-        let mut buffer = Vec::new();
-        if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut buffer) {
-            eprintln!("could not encode custom metrics: {}", e);
-        };
-        let mut res = match String::from_utf8(buffer.clone()) {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!("custom metrics could not be from_utf8'd: {}", e);
-                String::default()
-            }
-        };
-        buffer.clear();
+    fn log_level_reply(level: &LogLevel, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+        let expires_in = level.expires_in().map(|expires_in| expires_in.as_secs_f64());
+        warp::reply::with_status(
+            warp::reply::json(&json!({
+                "filter": level.filter(),
+                "default": level.default_filter(),
+                "expires_in_seconds": expires_in,
+            })),
+            status,
+        )
+    }
 
-        let mut buffer = Vec::new();
-        if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
-            eprintln!("could not encode prometheus metrics: {}", e);
-        };
-        let res_custom = match String::from_utf8(buffer.clone()) {
-            Ok(v) => v,
-            Err(e) => {
-                eprintln!("prometheus metrics could not be from_utf8'd: {}", e);
-                String::default()
+    /// The log filter in force
+    pub async fn log_level(level: LogLevel) -> Result<impl warp::Reply, Infallible> {
+        Ok(log_level_reply(&level, StatusCode::OK))
+    }
+
+    /// Change the log filter from a body of `{"filter": "info,rustyhello=debug", "ttl_seconds": 300}`
+    pub async fn set_log_level(level: LogLevel, body: Value) -> Result<impl warp::Reply, Infallible> {
+        let filter = match body["filter"].as_str() {
+            Some(filter) => filter,
+            None => {
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&json!({"error": "filter is required"})),
+                    StatusCode::BAD_REQUEST,
+                ))
             }
         };
-        buffer.clear();
+        let ttl = body["ttl_seconds"]
+            .as_f64()
+            .and_then(|ttl| Duration::try_from_secs_f64(ttl).ok())
+            .filter(|ttl| !ttl.is_zero());
+        Ok(match level.set(filter, ttl) {
+            Ok(()) => log_level_reply(&level, StatusCode::OK),
+            Err(e) => warp::reply::with_status(warp::reply::json(&json!({ "error": e })), StatusCode::BAD_REQUEST),
+        })
+    }
 
-        res.push_str(&res_custom);
-        Ok(res)
+    /// Revert to the default log filter
+    pub async fn reset_log_level(level: LogLevel) -> Result<impl warp::Reply, Infallible> {
+        if let Err(e) = level.reset() {
+            warn!("Could not reset the log filter: {}", e);
+        }
+        Ok(log_level_reply(&level, StatusCode::OK))
+    }
+
+    /// provide [Prometheus](https://prometheus.io) metrics
+    ///
+    /// Clients accepting `application/openmetrics-text` are given the OpenMetrics format including exemplars.
+    /// The response is compressed with gzip or zstd when the client accepts it.
+    pub async fn metrics(
+        exposition: Exposition,
+        accept: Option<String>,
+        accept_encoding: Option<String>,
+    ) -> Result<warp::reply::Response, Infallible> {
+        debug!("Returning metrics");
+        let format = Format::negotiate(accept.as_deref());
+        let encoding = Encoding::negotiate(accept_encoding.as_deref());
+        let mut response = warp::reply::Response::new(exposition.body(format, encoding).await);
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+        headers.insert(header::VARY, HeaderValue::from_static("accept, accept-encoding"));
+        if let Some(content_encoding) = encoding.content_encoding() {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(content_encoding));
+        }
+        Ok(response)
     }
 }
 
@@ -366,14 +634,163 @@ mod tests {
         hp0.tick();
         let (happy, detail) = hc0.status();
         assert!(!happy);
-        assert!(detail[&hp0.name]);
-        assert!(!detail[&hp1.name]);
+        assert_eq!(detail.get(&hp0.name), Some(true));
+        assert_eq!(detail.get(&hp1.name), Some(false));
 
         hp1.tick();
 
         let (happy, detail) = hc0.status();
         assert!(happy);
-        assert!(detail[&hp0.name]);
-        assert!(detail[&hp1.name]);
+        assert_eq!(detail.get(&hp0.name), Some(true));
+        assert_eq!(detail.get(&hp1.name), Some(true));
+    }
+
+    #[tokio::test]
+    async fn health_listen_per_service_registry() {
+        //! Test that multiple health servers can run in one process each exporting their own registry
+        use warp::hyper::{body, Client};
+
+        let liveness = HealthCheck::new("liveness");
+        let readyness = HealthCheck::new("readyness");
+        let mut servers = Vec::new();
+        for (name, port) in [("one", 7982), ("two", 7983)] {
+            let metrics = Metrics::new(name, "test");
+            let (kill, _kill_rx) = mpsc::channel(1);
+            let info = ServiceInfo::new(&crate::UServiceConfig::new(name));
+            let access_log = AccessLog::new(&Default::default());
+            servers.push(health_listen("health", port, &liveness, &readyness, kill, &metrics, &info, &access_log).await);
+        }
+
+        let client = Client::new();
+        for (name, port) in [("one", 7982), ("two", 7983)] {
+            // The first request creates the http series which carry the service label
+            let uri = format!("http://localhost:{}/health/metrics", port).parse().unwrap();
+            client.get(uri).await.unwrap();
+            let uri = format!("http://localhost:{}/health/metrics", port).parse().unwrap();
+            let resp = client.get(uri).await.unwrap();
+            let text = body::to_bytes(resp.into_body()).await.unwrap();
+            let text = String::from_utf8(text.to_vec()).unwrap();
+            assert!(text.contains(&format!("service=\"{}\"", name)));
+
+            let uri = format!("http://localhost:{}/health/info", port).parse().unwrap();
+            let resp = client.get(uri).await.unwrap();
+            assert!(resp.headers().contains_key(crate::logging::REQUEST_ID_HEADER));
+            let text = body::to_bytes(resp.into_body()).await.unwrap();
+            let text = String::from_utf8(text.to_vec()).unwrap();
+            assert!(text.contains(&format!("\"service\":\"{}\"", name)));
+            assert!(text.contains("\"uptime_seconds\""));
+        }
+
+        // Idle pooled connections would hold up the graceful shutdown of the servers
+        drop(client);
+        for server in servers {
+            server.channel.send(()).await.unwrap();
+            server.handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_content_negotiation() {
+        //! Test that the metrics are given in the OpenMetrics format or compressed only when it is accepted
+        let metrics = Metrics::new("negotiation", "test");
+        metrics.counter("jobs", "Jobs run").unwrap().inc();
+        let filter = filters::prometheus_metrics(metrics);
+
+        let resp = warp::test::request().path("/metrics").reply(&filter).await;
+        let text = String::from_utf8(resp.body().to_vec()).unwrap();
+        assert!(text.contains("# TYPE jobs counter"));
+        assert!(!text.contains("# EOF"));
+
+        let resp = warp::test::request()
+            .path("/metrics")
+            .header("accept", "application/openmetrics-text; version=1.0.0")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.headers()["content-type"], crate::openmetrics::CONTENT_TYPE);
+        let text = String::from_utf8(resp.body().to_vec()).unwrap();
+        assert!(text.contains("jobs_total{"));
+        assert!(text.ends_with("# EOF\n"));
+
+        let resp = warp::test::request()
+            .path("/metrics")
+            .header("accept-encoding", "gzip")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.headers()["content-encoding"], "gzip");
+        assert_eq!(&resp.body()[..2], &[0x1f, 0x8b]);
+    }
+
+    #[tokio::test]
+    async fn log_level_runtime_change() {
+        //! Test that the log filter can be read, changed and reverts after its time to live
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (layer, level) = crate::logging::LogLevel::new(tracing_subscriber::EnvFilter::new("info"));
+        let _default = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+        let filter = filters::log_level(Some(level.clone()));
+        assert!(!tracing::enabled!(target: "shop", tracing::Level::DEBUG));
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/loglevel")
+            .json(&serde_json::json!({"filter": "info,shop=debug", "ttl_seconds": 0.05}))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), warp::http::StatusCode::OK);
+        assert!(tracing::enabled!(target: "shop", tracing::Level::DEBUG));
+        assert!(!tracing::enabled!(target: "other", tracing::Level::DEBUG));
+
+        let resp = warp::test::request().path("/loglevel").reply(&filter).await;
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["filter"], "info,shop=debug");
+        assert_eq!(body["default"], "info");
+        assert!(body["expires_in_seconds"].as_f64().unwrap() <= 0.05);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/loglevel")
+            .json(&serde_json::json!({"filter": "shop=loud"}))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), warp::http::StatusCode::BAD_REQUEST);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(level.filter(), "info");
+        assert!(!tracing::enabled!(target: "shop", tracing::Level::DEBUG));
+        assert!(!warp::test::request().path("/loglevel").matches(&filters::log_level(None)).await);
+    }
+
+    #[test]
+    fn health_check_events() {
+        //! Test that evaluating a HealthCheck publishes probe and overall transitions
+        let mut hp0 = HealthProbe::new("HealthCheck0", Duration::from_millis(15));
+        let hc0 = HealthCheck::new("simple");
+        hc0.add(&hp0);
+
+        let mut events = hc0.subscribe();
+        let happy = hc0.watch();
+
+        hc0.evaluate();
+        assert!(events.try_recv().is_err());
+
+        thread::sleep(Duration::from_millis(20));
+        hc0.evaluate();
+        let probe_event = events.try_recv().unwrap();
+        assert_eq!(probe_event.check, "simple");
+        assert_eq!(probe_event.probe.as_deref(), Some("HealthCheck0"));
+        assert!(probe_event.previous && !probe_event.current);
+        let overall_event = events.try_recv().unwrap();
+        assert_eq!(overall_event.probe, None);
+        assert!(overall_event.previous && !overall_event.current);
+        assert!(!*happy.borrow());
+
+        hc0.evaluate();
+        assert!(events.try_recv().is_err());
+
+        hp0.tick();
+        hc0.evaluate();
+        assert!(events.try_recv().unwrap().current);
+        assert!(events.try_recv().unwrap().current);
+        assert!(*happy.borrow());
     }
 }
@@ -0,0 +1,161 @@
+//! Probe measuring the scheduling delay of the tokio event loop
+//!
+//! A task repeatedly sleeps for a short interval and measures how late it is woken. Blocking code on the runtime delays the wake up,
+//! so the delay is recorded to the `event_loop_lag_seconds` histogram and the [HealthProbe] is only [tick](HealthProbe::tick)ed while the delay is within the threshold.
+//! If the delay stays above the threshold for longer than the margin of the [HealthProbe] then the [HealthCheck](crate::k8slifecycle::HealthCheck) fails.
+
+use crate::env_parse;
+use crate::k8slifecycle::HealthProbe;
+use crate::metrics::Metrics;
+use crate::{spawn_component, HandleChannel};
+use prometheus::{Histogram, HistogramOpts};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// Configuration of the event loop lag probe
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct LagConfig {
+    /// Time slept between measurements
+    pub interval: Duration,
+    /// Largest delay for the [HealthProbe] to be ticked
+    pub threshold: Duration,
+    /// Time the delay may stay above the threshold before the liveness check fails
+    pub margin: Duration,
+}
+
+impl Default for LagConfig {
+    fn default() -> LagConfig {
+        LagConfig {
+            interval: Duration::from_millis(100),
+            threshold: Duration::from_millis(50),
+            margin: Duration::from_secs(5),
+        }
+    }
+}
+
+impl LagConfig {
+    /// Create a [LagConfig] from defaults overridden by environment variables
+    ///
+    ///  * `USERVICE_LAG_INTERVAL` milliseconds between measurements
+    ///  * `USERVICE_LAG_THRESHOLD` milliseconds of delay tolerated
+    ///  * `USERVICE_LAG_MARGIN` seconds the delay may exceed the threshold before failing liveness
+    pub fn from_env() -> LagConfig {
+        let mut config = LagConfig::default();
+
+        if let Some(ms) = env_parse("USERVICE_LAG_INTERVAL", |v| {
+            v.parse::<u64>().ok().filter(|ms| *ms > 0)
+        }) {
+            config.interval = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_parse("USERVICE_LAG_THRESHOLD", |v| v.parse::<u64>().ok()) {
+            config.threshold = Duration::from_millis(ms);
+        }
+        if let Some(secs) = env_parse("USERVICE_LAG_MARGIN", |v| {
+            v.parse::<u64>().ok().filter(|secs| *secs > 0)
+        }) {
+            config.margin = Duration::from_secs(secs);
+        }
+        config
+    }
+}
+
+/// Create the histogram of event loop lag
+fn lag_histogram() -> Histogram {
+    Histogram::with_opts(
+        HistogramOpts::new(
+            "event_loop_lag_seconds",
+            "Delay between when the event loop should and did wake a task",
+        )
+        .buckets(vec![
+            0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+        ]),
+    )
+    .expect("metric can be created")
+}
+
+/// Start the event loop lag probe as a [HandleChannel] to be managed by the [UService](crate::UService)
+///
+/// The delay is measured every `interval` and the [HealthProbe] is ticked when the delay is no more than `threshold`.
+/// A warning is logged when the delay first exceeds the threshold and a message when it is back within it.
+pub async fn lag_probe(
+    probe: &HealthProbe,
+    interval: Duration,
+    threshold: Duration,
+    metrics: &Metrics,
+) -> HandleChannel {
+    info!("Starting event loop lag probe");
+
+    let histogram = lag_histogram();
+    metrics.register(&histogram);
+
+    let mut probe = probe.clone();
+    let (channel, mut rx) = mpsc::channel(1);
+
+    let handle = spawn_component("lag_probe", async move {
+        let mut lagging = false;
+        loop {
+            let start = Instant::now();
+            tokio::select! {
+                _ = sleep(interval) => {},
+                _ = rx.recv() => break,
+            }
+            let lag = start.elapsed().saturating_sub(interval);
+            histogram.observe(lag.as_secs_f64());
+
+            // Only changes are logged, the histogram and the probe carry the lag in between
+            if lag <= threshold {
+                probe.tick();
+                if lagging {
+                    info!("Event loop lag of {:?} back within {:?}", lag, threshold);
+                }
+            } else if !lagging {
+                warn!("Event loop lag of {:?} exceeds {:?}", lag, threshold);
+            }
+            lagging = lag > threshold;
+        }
+        info!("Event loop lag probe closed");
+    });
+
+    HandleChannel { handle, channel }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lag_probe_blocking() {
+        //! Test that blocking the event loop stops the probe being ticked until the loop recovers
+        let probe = HealthProbe::new("EventLoopLag", Duration::from_millis(100));
+        let metrics = Metrics::new("test", "test");
+
+        let lag = lag_probe(
+            &probe,
+            Duration::from_millis(10),
+            Duration::from_millis(20),
+            &metrics,
+        )
+        .await;
+        sleep(Duration::from_millis(50)).await;
+        assert!(probe.valid());
+
+        std::thread::sleep(Duration::from_millis(300));
+        sleep(Duration::from_millis(1)).await;
+        assert!(!probe.valid());
+
+        sleep(Duration::from_millis(50)).await;
+        assert!(probe.valid());
+        let families = metrics.registry().gather();
+        let family = families
+            .iter()
+            .find(|family| family.get_name() == "event_loop_lag_seconds")
+            .expect("lag histogram registered");
+        assert!(family.get_metric()[0].get_histogram().get_sample_count() > 0);
+
+        lag.channel.send(()).await.unwrap();
+        lag.handle.await.unwrap();
+    }
+}
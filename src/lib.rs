@@ -1,11 +1,53 @@
 //! Create a micro service
+pub mod accesslog;
+pub mod buildinfo;
+pub mod cardinality;
+pub mod exposition;
+#[cfg(feature = "grpc")]
+pub mod grpchealth;
+pub mod httpmetrics;
 pub mod k8slifecycle;
+pub mod lagprobe;
+pub mod logfile;
+pub mod logging;
+pub mod metrics;
+pub mod openmetrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+#[cfg(feature = "trace")]
+pub mod otlptrace;
+#[cfg(feature = "hdr")]
+pub mod percentiles;
+pub mod pushgateway;
+pub mod ratelimit;
+pub mod resourceprobe;
+pub mod runtimemetrics;
 mod sampleservice;
+pub mod statsd;
 
-use crate::k8slifecycle::health_listen;
+#[cfg(feature = "grpc")]
+use crate::grpchealth::grpc_health_listen;
+use crate::accesslog::{AccessLog, AccessLogConfig};
+use crate::buildinfo::ServiceInfo;
+use crate::k8slifecycle::{health_listen, health_monitor};
 use crate::k8slifecycle::{HealthCheck, HealthProbe};
+use crate::lagprobe::{lag_probe, LagConfig};
+use crate::metrics::{Metrics, MetricsConfig};
+#[cfg(feature = "otlp")]
+use crate::otlp::{otlp_exporter, OtlpConfig};
+#[cfg(feature = "trace")]
+use crate::otlp::Signal;
+#[cfg(feature = "trace")]
+use crate::otlptrace::trace_exporter;
+use crate::pushgateway::{pushgateway, PushgatewayConfig};
+use crate::ratelimit::log_rate_limit;
+use crate::resourceprobe::{resource_probe, Resource, ResourceConfig};
+use crate::runtimemetrics::runtime_metrics;
 use crate::sampleservice::sample_listen;
+use crate::statsd::{statsd_exporter, StatsdConfig};
 use futures::future;
+use serde::Serialize;
+use std::env;
 use std::mem;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
@@ -14,11 +56,81 @@ use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
 use tokio::time::sleep;
 use warp::hyper::Client;
-use log::{info};
+use std::future::Future;
+use tracing::{debug, info, info_span, warn, Instrument};
 
 
+#[derive(Clone, Debug, Serialize)]
 pub struct UServiceConfig {
     pub name: String,
+    /// Environment the service is deployed to, used to label metrics
+    pub env: String,
+    /// Limits for the process resource probes
+    pub resources: ResourceConfig,
+    /// Interval, threshold and margin of the event loop lag probe
+    pub lag: LagConfig,
+    /// Naming and labels of metrics
+    pub metrics: MetricsConfig,
+    /// Access log of the http servers
+    pub access_log: AccessLogConfig,
+    /// Pushgateway to push metrics to, if any
+    pub pushgateway: Option<PushgatewayConfig>,
+    /// StatsD agent to mirror metrics to, if any
+    pub statsd: Option<StatsdConfig>,
+    /// OpenTelemetry collector to export metrics to over OTLP, if any
+    #[cfg(feature = "otlp")]
+    pub otlp: Option<OtlpConfig>,
+    /// OpenTelemetry collector to export trace spans to over OTLP, if any
+    #[cfg(feature = "trace")]
+    pub traces: Option<OtlpConfig>,
+}
+
+impl UServiceConfig {
+    /// Create a [UServiceConfig] with default settings
+    pub fn new(name: &str) -> UServiceConfig {
+        UServiceConfig {
+            name: name.to_string(),
+            env: String::from("dev"),
+            resources: ResourceConfig::default(),
+            lag: LagConfig::default(),
+            metrics: MetricsConfig::default(),
+            access_log: AccessLogConfig::default(),
+            pushgateway: None,
+            statsd: None,
+            #[cfg(feature = "otlp")]
+            otlp: None,
+            #[cfg(feature = "trace")]
+            traces: None,
+        }
+    }
+
+    /// Create a [UServiceConfig] with settings overridden by `USERVICE_` environment variables
+    pub fn from_env(name: &str) -> UServiceConfig {
+        #[allow(unused_mut)]
+        let mut config = UServiceConfig {
+            name: name.to_string(),
+            env: env::var("USERVICE_ENV").unwrap_or_else(|_| String::from("dev")),
+            resources: ResourceConfig::from_env(),
+            lag: LagConfig::from_env(),
+            metrics: MetricsConfig::from_env(),
+            access_log: AccessLogConfig::from_env(),
+            pushgateway: PushgatewayConfig::from_env(name),
+            statsd: StatsdConfig::from_env(),
+            #[cfg(feature = "otlp")]
+            otlp: None,
+            #[cfg(feature = "trace")]
+            traces: None,
+        };
+        #[cfg(feature = "otlp")]
+        {
+            config.otlp = OtlpConfig::from_env(&config);
+        }
+        #[cfg(feature = "trace")]
+        {
+            config.traces = OtlpConfig::signal_from_env(Signal::Traces, &config);
+        }
+        config
+    }
 }
 
 #[derive(Debug)]
@@ -27,8 +139,51 @@ pub struct HandleChannel {
     pub channel: mpsc::Sender<()>,
 }
 
+/// Spawn the task of a component in a `component` span, logging when it starts and stops
+pub fn spawn_component<F>(name: &str, future: F) -> tokio::task::JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let span = info_span!("component", component = name);
+    tokio::spawn(
+        async move {
+            debug!("Component started");
+            future.await;
+            debug!("Component stopped");
+        }
+        .instrument(span),
+    )
+}
+
+/// Read and parse an environment variable, warning if it is set but invalid
+pub(crate) fn env_parse<T>(name: &str, parse: impl Fn(&str) -> Option<T>) -> Option<T> {
+    read_env(name, parse, false)
+}
+
+/// [env_parse] for configuration read before logging is initialised, reporting invalid values on stderr
+pub(crate) fn env_parse_early<T>(name: &str, parse: impl Fn(&str) -> Option<T>) -> Option<T> {
+    read_env(name, parse, true)
+}
+
+fn read_env<T>(name: &str, parse: impl Fn(&str) -> Option<T>, early: bool) -> Option<T> {
+    let value = env::var(name).ok()?;
+    let parsed = parse(&value);
+    if parsed.is_none() {
+        if early {
+            eprintln!("Ignoring invalid {}: {}", name, value);
+        } else {
+            warn!("Ignoring invalid {}: {}", name, value);
+        }
+    }
+    parsed
+}
+
 pub struct UService {
     pub name: String,
+    pub config: UServiceConfig,
+    metrics: Metrics,
+    info: ServiceInfo,
+    access_log: AccessLog,
     // pub rt: tokio::runtime::Runtime,
     channels: Arc<Mutex<Vec<mpsc::Sender<()>>>>,
     handles: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
@@ -36,14 +191,41 @@ pub struct UService {
 
 impl UService {
     pub fn new(name: &str) -> UService {
+        UService::from_config(&UServiceConfig::new(name))
+    }
+
+    pub fn from_config(config: &UServiceConfig) -> UService {
+        let metrics = Metrics::with_config(&config.name, &config.env, &config.metrics);
+        let info = ServiceInfo::new(config);
+        info.register(&metrics);
+
         UService {
-            name: name.to_string(),
+            name: config.name.clone(),
+            config: config.clone(),
+            metrics,
+            info,
+            access_log: AccessLog::new(&config.access_log),
 
             channels: Arc::new(Mutex::new(vec![])),
             handles: Arc::new(Mutex::new(vec![])),
         }
     }
 
+    /// Handle to the [Metrics] of the service for components to register their collectors
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// [AccessLog] shared by the http servers of the service
+    pub fn access_log(&self) -> &AccessLog {
+        &self.access_log
+    }
+
+    /// Build and runtime [ServiceInfo] of the service
+    pub fn info(&self) -> &ServiceInfo {
+        &self.info
+    }
+
     pub fn add(&self, hc: HandleChannel) {
         self.handles.lock().unwrap().push(hc.handle);
         self.channels.lock().unwrap().push(hc.channel);
@@ -52,23 +234,33 @@ impl UService {
     pub async fn shutdown(channels: Arc<Mutex<Vec<mpsc::Sender<()>>>>) {
         let ben = channels.lock().unwrap().clone();
 
-        for channel in ben.iter() {
-            let channel_rx = channel.send(()).await;
-            match channel_rx {
-                Ok(_v) => info!("Shutdown signal sent"),
-                Err(e) => info!("Error sending close signal: {:?}", e),
+        async {
+            for channel in ben.iter() {
+                let channel_rx = channel.send(()).await;
+                match channel_rx {
+                    Ok(_v) => info!("Shutdown signal sent"),
+                    Err(e) => info!("Error sending close signal: {:?}", e),
+                }
             }
         }
+        .instrument(info_span!("shutdown", phase = "signal"))
+        .await
     }
 
     pub async fn join(&self) {
-        let mut handles = self
-            .handles
-            .lock()
-            .expect("Could not lock mutex for handles");
-        info!("Waiting for services: {:?}", handles);
-        future::join_all(mem::take(&mut *handles)).await;
-        info!("Services completed");
+        let handles = mem::take(
+            &mut *self
+                .handles
+                .lock()
+                .expect("Could not lock mutex for handles"),
+        );
+        async {
+            info!("Waiting for services: {:?}", handles);
+            future::join_all(handles).await;
+            info!("Services completed");
+        }
+        .instrument(info_span!("join"))
+        .await
     }
 }
 
@@ -79,7 +271,7 @@ async fn simple_loop(probe: &HealthProbe) -> HandleChannel {
     let (channel, mut rx) = mpsc::channel(1);
     let alive = Arc::new(AtomicBool::new(true));
 
-    let handle = tokio::spawn(async move {
+    let handle = spawn_component("simple_loop", async move {
         let alive_recv = alive.clone();
         tokio::spawn(async move {
             // Speawn a receive channel to close the loop when signal received
@@ -117,23 +309,72 @@ pub async fn start_async(uservice: &UService, liveness: &HealthCheck, readyness:
     liveness.add(&time_loop);
 
     uservice.add(simple_loop(&time_loop).await);
-    uservice.add(health_listen("health", 7979, &liveness, &readyness, channel_http_kill).await);
-    uservice.add(sample_listen("sample", 8080).await);
+
+    let lag = &uservice.config.lag;
+    let event_loop_lag = HealthProbe::new("EventLoopLag", lag.margin);
+    liveness.add(&event_loop_lag);
+
+    uservice.add(lag_probe(&event_loop_lag, lag.interval, lag.threshold, uservice.metrics()).await);
+
+    runtime_metrics(&tokio::runtime::Handle::current(), uservice.metrics());
+
+    let resources = &uservice.config.resources;
+    let mut watched = vec![
+        (Resource::Memory, resources.memory),
+        (Resource::FileDescriptors, resources.file_descriptors),
+    ];
+    for path in resources.disk_paths.iter() {
+        watched.push((Resource::Disk(path.clone()), resources.disk));
+    }
+    for (resource, watermark) in watched {
+        let probe = HealthProbe::new(&resource.to_string(), resources.interval * 3);
+        readyness.add(&probe);
+        uservice.add(resource_probe(resource, &probe, watermark, resources.interval).await);
+    }
+    uservice.add(health_monitor(Duration::from_secs(1), &[liveness, readyness]).await);
+    uservice.add(health_listen("health", 7979, liveness, readyness, channel_http_kill, uservice.metrics(), uservice.info(), uservice.access_log()).await);
+    #[cfg(feature = "grpc")]
+    uservice.add(grpc_health_listen(7980, liveness, readyness).await);
+    uservice.add(sample_listen("sample", 8080, uservice.metrics(), uservice.access_log()).await);
+    if let Some(rate_limit) = logging::rate_limit() {
+        uservice.add(log_rate_limit(&rate_limit, uservice.metrics()).await);
+    }
+    if let Some(config) = &uservice.config.pushgateway {
+        uservice.add(pushgateway(config, uservice.metrics()).await);
+    }
+    if let Some(config) = &uservice.config.statsd {
+        uservice.add(statsd_exporter(config, uservice.metrics()).await);
+    }
+    #[cfg(feature = "otlp")]
+    if let Some(config) = &uservice.config.otlp {
+        uservice.add(otlp_exporter(config, uservice.metrics(), uservice.info()).await);
+    }
+    #[cfg(feature = "trace")]
+    if let Some(config) = &uservice.config.traces {
+        uservice.add(trace_exporter(config).await);
+    }
 
     let channels_register = uservice.channels.clone();
-    tokio::spawn(async move {
+    spawn_component("signal_handler", async move {
         let mut sig_terminate =
             signal(SignalKind::terminate()).expect("Register terminate signal handler");
         let mut sig_quit = signal(SignalKind::quit()).expect("Register quit signal handler");
         let mut sig_hup = signal(SignalKind::hangup()).expect("Register hangup signal handler");
 
         info!("registered signal handlers");
-        tokio::select! {
-            _ = rx_http_kill.recv() => info!("Received HTTP kill signal"),
-            _ = sig_terminate.recv() => info!("Received TERM signal"),
-            _ = sig_quit.recv() => info!("Received QUIT signal"),
-            _ = sig_hup.recv() => info!("Received HUP signal"),
-        };
+        loop {
+            tokio::select! {
+                _ = rx_http_kill.recv() => info!("Received HTTP kill signal"),
+                _ = sig_terminate.recv() => info!("Received TERM signal"),
+                _ = sig_quit.recv() => info!("Received QUIT signal"),
+                _ = sig_hup.recv() => {
+                    info!("Received HUP signal, reopening log files");
+                    logfile::reopen();
+                    continue;
+                }
+            };
+            break;
+        }
         info!("Signal handler triggered to start Shutdown");
 
         UService::shutdown(channels_register).await;
@@ -146,11 +387,12 @@ pub async fn start_async(uservice: &UService, liveness: &HealthCheck, readyness:
 /// Start the service (including starting the runtime (ie tokio))
 pub fn start(config: &UServiceConfig) {
 
+    let _span = info_span!("service").entered();
     info!("uService: Start");
     let liveness = HealthCheck::new("liveness");
     let readyness = HealthCheck::new("readyness");
 
-    let uservice = UService::new(&config.name);
+    let uservice = UService::from_config(config);
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
@@ -174,9 +416,7 @@ mod tests {
     async fn service_loading() {
         println!("Loading uService");
 
-        let my_config = UServiceConfig {
-            name: String::from("test0"),
-        };
+        let my_config = UServiceConfig::new("test0");
 
         let ben = thread::spawn(move || {
             start(&my_config);
@@ -0,0 +1,364 @@
+//! Log files with rotation
+//!
+//! A [LogFile] appends to a path and rotates it when it grows past a size or at midnight UTC, as set by its [Rotation].
+//! The file is renamed to `<path>.1`, shifting older files up to `<path>.<keep>` and removing the oldest,
+//! and optionally compressed to `<path>.1.gz` in the background.
+//!
+//! Open log files are reopened by [reopen], called when the service receives `SIGHUP`,
+//! so an external `logrotate` can move them away and have the service start a new file.
+
+use flate2::write::GzEncoder;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// When a log file is rotated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// Never, leaving rotation to an external tool
+    Never,
+    /// Before a write would take it past a size in bytes
+    Size(u64),
+    /// On the first write of each day in UTC
+    Daily,
+}
+
+impl Rotation {
+    /// Parse `never`, `daily` or a size in bytes with an optional `KB`, `MB` or `GB` suffix
+    pub fn parse(rotation: &str) -> Option<Rotation> {
+        let rotation = rotation.trim().to_ascii_uppercase();
+        match rotation.as_str() {
+            "NEVER" => return Some(Rotation::Never),
+            "DAILY" => return Some(Rotation::Daily),
+            _ => {}
+        }
+        let digits = rotation.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let scale = match rotation[digits.len()..].trim_end_matches('B') {
+            "" => 1,
+            "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            _ => return None,
+        };
+        let size = digits.trim().parse::<u64>().ok()?.checked_mul(scale)?;
+        (size > 0).then_some(Rotation::Size(size))
+    }
+}
+
+/// Configuration of a log file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFileConfig {
+    /// Path written to
+    pub path: PathBuf,
+    /// When the file is rotated
+    pub rotation: Rotation,
+    /// Number of rotated files kept
+    pub keep: usize,
+    /// Gzip rotated files
+    pub compress: bool,
+}
+
+impl LogFileConfig {
+    /// Create a [LogFileConfig] for a path that is never rotated
+    pub fn new(path: impl Into<PathBuf>) -> LogFileConfig {
+        LogFileConfig {
+            path: path.into(),
+            rotation: Rotation::Never,
+            keep: 7,
+            compress: false,
+        }
+    }
+
+    /// Path of the rotated file of an index, starting at 1 for the most recent
+    fn rotated(&self, index: usize, compressed: bool) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        if compressed {
+            path.push(".gz");
+        }
+        PathBuf::from(path)
+    }
+}
+
+/// Days since the epoch in UTC
+fn day_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400
+}
+
+struct State {
+    config: LogFileConfig,
+    file: File,
+    /// Bytes in the file
+    size: u64,
+    /// Day the file was started
+    day: u64,
+    /// Compression of the last rotated file, if running
+    compressing: Option<JoinHandle<()>>,
+}
+
+impl State {
+    fn open(config: &LogFileConfig) -> io::Result<State> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let metadata = file.metadata()?;
+        Ok(State {
+            config: config.clone(),
+            size: metadata.len(),
+            day: day_of(metadata.modified().unwrap_or_else(|_| SystemTime::now())),
+            file,
+            compressing: None,
+        })
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let reopened = State::open(&self.config)?;
+        self.file = reopened.file;
+        self.size = reopened.size;
+        self.day = reopened.day;
+        Ok(())
+    }
+
+    fn due(&self, len: usize) -> bool {
+        match self.config.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max) => self.size > 0 && self.size + len as u64 > max,
+            Rotation::Daily => self.size > 0 && day_of(SystemTime::now()) != self.day,
+        }
+    }
+
+    /// Shift the rotated files up, move the file to the first and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(compressing) = self.compressing.take() {
+            compressing.join().ok();
+        }
+        let config = &self.config;
+        for compressed in [false, true] {
+            remove_if_exists(&config.rotated(config.keep, compressed))?;
+            for index in (1..config.keep).rev() {
+                rename_if_exists(
+                    &config.rotated(index, compressed),
+                    &config.rotated(index + 1, compressed),
+                )?;
+            }
+        }
+        if config.keep == 0 {
+            remove_if_exists(&config.path)?;
+        } else {
+            fs::rename(&config.path, config.rotated(1, false))?;
+            if config.compress {
+                let config = config.clone();
+                self.compressing = Some(thread::spawn(move || {
+                    // Logging would wait on this file, which waits for the compression to finish
+                    if let Err(e) = compress(&config.rotated(1, false), &config.rotated(1, true)) {
+                        eprintln!("Could not compress {}: {}", config.path.display(), e);
+                    }
+                }));
+            }
+        }
+        self.reopen()
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Gzip a file, removing the original
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut gzip = GzEncoder::new(File::create(to)?, flate2::Compression::default());
+    io::copy(&mut File::open(from)?, &mut gzip)?;
+    gzip.finish()?.sync_all()?;
+    fs::remove_file(from)
+}
+
+/// Log files open for [reopen]
+static OPEN: Mutex<Vec<Weak<Mutex<State>>>> = Mutex::new(Vec::new());
+
+/// Reopen every open [LogFile] at its path
+pub fn reopen() {
+    let files: Vec<_> = {
+        let mut open = OPEN.lock().unwrap();
+        open.retain(|file| file.strong_count() > 0);
+        open.iter().filter_map(Weak::upgrade).collect()
+    };
+    for file in files {
+        let mut state = file.lock().unwrap();
+        if let Err(e) = state.reopen() {
+            drop(state);
+            warn!("Could not reopen log file: {}", e);
+        }
+    }
+}
+
+/// A log file rotated as configured
+///
+/// Each write is expected to be whole lines, as written by the tracing subscriber, so lines are not split across files.
+#[derive(Clone)]
+pub struct LogFile {
+    state: Arc<Mutex<State>>,
+}
+
+impl LogFile {
+    /// Open a log file for appending, creating it if needed
+    pub fn open(config: &LogFileConfig) -> io::Result<LogFile> {
+        let state = Arc::new(Mutex::new(State::open(config)?));
+        OPEN.lock().unwrap().push(Arc::downgrade(&state));
+        Ok(LogFile { state })
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.due(buf.len()) {
+            // Logging the failure could write to this file, so it is reported on stderr
+            if let Err(e) = state.rotate() {
+                eprintln!("Could not rotate {}: {}", state.config.path.display(), e);
+                // Keep writing to the file and retry once another rotation is due, not on every write
+                state.size = 0;
+            }
+            state.day = day_of(SystemTime::now());
+        }
+        state.file.write_all(buf)?;
+        state.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFile;
+
+    fn make_writer(&'a self) -> LogFile {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("logfile-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn size_rotation_kept_and_compressed() {
+        //! Test that files are rotated by size, compressed and only the configured number kept
+        let dir = test_dir("size");
+        let config = LogFileConfig {
+            rotation: Rotation::Size(10),
+            keep: 2,
+            compress: true,
+            ..LogFileConfig::new(dir.join("service.log"))
+        };
+        let mut file = LogFile::open(&config).unwrap();
+        for line in ["one 1234\n", "two 1234\n", "three 12\n", "four 123\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        if let Some(compressing) = file.state.lock().unwrap().compressing.take() {
+            compressing.join().unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&config.path).unwrap(), "four 123\n");
+        let mut newest = String::new();
+        flate2::read::GzDecoder::new(File::open(config.rotated(1, true)).unwrap())
+            .read_to_string(&mut newest)
+            .unwrap();
+        assert_eq!(newest, "three 12\n");
+        assert!(config.rotated(2, true).exists());
+        assert!(!config.rotated(3, true).exists() && !config.rotated(1, false).exists());
+
+        assert_eq!(Rotation::parse("10MB"), Some(Rotation::Size(10 << 20)));
+        assert_eq!(Rotation::parse("512k"), Some(Rotation::Size(512 << 10)));
+        assert_eq!(Rotation::parse("Daily"), Some(Rotation::Daily));
+        assert_eq!(Rotation::parse("0"), None);
+        assert_eq!(Rotation::parse("10TB"), None);
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn failed_rotation_backs_off() {
+        //! Test that a failed rotation keeps writing to the file and is not retried until another rotation is due
+        let dir = test_dir("failed");
+        let config = LogFileConfig {
+            rotation: Rotation::Size(10),
+            keep: 1,
+            ..LogFileConfig::new(dir.join("service.log"))
+        };
+        // The rotated path cannot be removed, so rotating fails
+        fs::create_dir_all(config.rotated(1, false).join("blocked")).unwrap();
+        let mut file = LogFile::open(&config).unwrap();
+        for line in ["one 1234\n", "two 1234\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(file.state.lock().unwrap().size, 9);
+        assert_eq!(
+            fs::read_to_string(&config.path).unwrap(),
+            "one 1234\ntwo 1234\n"
+        );
+
+        fs::remove_dir_all(config.rotated(1, false)).unwrap();
+        file.write_all(b"three 12\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(config.rotated(1, false)).unwrap(),
+            "one 1234\ntwo 1234\n"
+        );
+        assert_eq!(fs::read_to_string(&config.path).unwrap(), "three 12\n");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn daily_rotation_and_reopen() {
+        //! Test that a file from a previous day is rotated and a file moved away is recreated on reopen
+        let dir = test_dir("daily");
+        let config = LogFileConfig {
+            rotation: Rotation::Daily,
+            ..LogFileConfig::new(dir.join("service.log"))
+        };
+        let mut file = LogFile::open(&config).unwrap();
+        file.write_all(b"yesterday\n").unwrap();
+        file.state.lock().unwrap().day -= 1;
+        file.write_all(b"today\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(config.rotated(1, false)).unwrap(),
+            "yesterday\n"
+        );
+        assert_eq!(fs::read_to_string(&config.path).unwrap(), "today\n");
+
+        let moved = dir.join("service.log.moved");
+        fs::rename(&config.path, &moved).unwrap();
+        reopen();
+        file.write_all(b"reopened\n").unwrap();
+        assert_eq!(fs::read_to_string(&moved).unwrap(), "today\n");
+        assert_eq!(fs::read_to_string(&config.path).unwrap(), "reopened\n");
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,711 @@
+//! Standardised logging of the service
+//!
+//! The crate logs through [tracing] and the subscriber installed by [init] writes events, filtered by `RUST_LOG`,
+//! either as human readable lines or as one JSON object per line for log collectors.
+//! Records of the `log` crate, as written by dependencies, are bridged into the subscriber.
+//!
+//! A JSON line carries the timestamp, level, target, message and fields of the event, the service, version and pod
+//! and the fields of the spans the event is in, such as the `request_id` and `trace_id` of a request.
+//!
+//! Events are written to stderr or to a [LogFile] rotated by size or day, as set by the [LogOutput].
+//!
+//! Repeated events are suppressed from the log output beyond a burst per window by the [RateLimit] filter set by [RateLimitConfig].
+//!
+//! The filter starts from `RUST_LOG` and can be changed at runtime through the [LogLevel] returned by [log_level],
+//! optionally reverting after a time to live. The health server exposes it at `/health/loglevel`.
+//!
+//! Servers started with [serve] handle every request in a `request` span carrying the method, path, route, request id,
+//! trace id, status and latency. The request id is taken from the `x-request-id` header, or generated when none is given,
+//! and returned in the `x-request-id` response header.
+//! The request id and the [Connection] of the request are also given to the filter served, for the access log.
+//!
+//! With the `trace` feature the `request` span is also the server span of an OpenTelemetry trace, see [otlptrace](crate::otlptrace),
+//! and its trace id is that of the trace when sampled.
+
+use crate::env_parse_early;
+use crate::logfile::{LogFile, LogFileConfig, Rotation};
+use crate::openmetrics::traceparent_trace_id;
+#[cfg(feature = "trace")]
+use crate::otlptrace;
+use crate::ratelimit::{RateLimit, RateLimitConfig, RateLimitKey};
+use serde_json::{Map, Value};
+use std::convert::Infallible;
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, IsTerminal};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, field, info, info_span, warn, Event, Instrument, Span, Subscriber};
+use tracing_log::{AsLog, NormalizeEvent};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+use warp::http::{HeaderMap, HeaderValue, Version};
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::server::conn::AddrStream;
+use warp::hyper::Server;
+use warp::{Filter, Rejection, Reply};
+
+/// Header carrying the id of a request
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Format of log lines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl LogFormat {
+    /// Parse `text` or `json`
+    pub fn parse(format: &str) -> Option<LogFormat> {
+        match format.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Where log lines are written
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogOutput {
+    Stderr,
+    /// A rotated log file
+    File(LogFileConfig),
+}
+
+/// Configuration of logging
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogConfig {
+    /// Format of log lines
+    pub format: LogFormat,
+    /// Where log lines are written
+    pub output: LogOutput,
+    /// Rate limiting of repeated events, if any
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Ratio of new traces sampled, if tracing
+    #[cfg(feature = "trace")]
+    pub trace_ratio: Option<f64>,
+    /// Name of the service
+    pub service: String,
+    /// Version of the service
+    pub version: String,
+    /// Name of the pod, if running in kubernetes
+    pub pod: Option<String>,
+}
+
+impl LogConfig {
+    /// Create a [LogConfig] writing human readable lines
+    pub fn new(service: &str) -> LogConfig {
+        LogConfig {
+            format: LogFormat::Text,
+            output: LogOutput::Stderr,
+            rate_limit: Some(RateLimitConfig::default()),
+            #[cfg(feature = "trace")]
+            trace_ratio: None,
+            service: service.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pod: None,
+        }
+    }
+
+    /// Create a [LogConfig] from environment variables
+    ///
+    ///  * `USERVICE_LOG_FORMAT` as `text` or `json`, defaulting to `text` in the `dev` environment and `json` elsewhere
+    ///  * `USERVICE_LOG_FILE` as the path of a log file to write to instead of stderr, rotated as set by
+    ///     * `USERVICE_LOG_ROTATE` as `never`, `daily` or a size such as `100MB`, defaulting to `never`
+    ///     * `USERVICE_LOG_KEEP` as the number of rotated files kept, defaulting to 7
+    ///     * `USERVICE_LOG_COMPRESS` as `true` to gzip rotated files
+    ///  * `USERVICE_LOG_RATE_LIMIT` as the events of a key let through in each window, 0 to turn rate limiting off
+    ///  * `USERVICE_LOG_RATE_WINDOW` as the seconds of a window
+    ///  * `USERVICE_LOG_RATE_KEY` as `callsite` or `message`
+    ///  * With the `trace` feature, tracing when spans are exported as set by [OtlpConfig::signal_from_env](crate::otlp::OtlpConfig::signal_from_env)
+    ///    with `OTEL_TRACES_SAMPLER_ARG` as the ratio of new traces sampled, defaulting to 1
+    ///  * `USERVICE_VERSION` for the version
+    ///  * `POD_NAME` as set from the kubernetes downward API
+    ///
+    /// Invalid values are reported on stderr as there is no logger yet.
+    pub fn from_env(service: &str) -> LogConfig {
+        let defaults = LogConfig::new(service);
+        let dev = env::var("USERVICE_ENV").map_or(true, |env| env == "dev");
+        let format = match env_parse_early("USERVICE_LOG_FORMAT", LogFormat::parse) {
+            Some(format) => format,
+            None if dev => LogFormat::Text,
+            None => LogFormat::Json,
+        };
+        let output = match env::var_os("USERVICE_LOG_FILE") {
+            Some(path) if !path.is_empty() => {
+                let mut file = LogFileConfig::new(path);
+                if let Some(rotation) = env_parse_early("USERVICE_LOG_ROTATE", Rotation::parse) {
+                    file.rotation = rotation;
+                }
+                if let Some(keep) = env_parse_early("USERVICE_LOG_KEEP", |v| v.parse().ok()) {
+                    file.keep = keep;
+                }
+                if let Some(compress) = env_parse_early("USERVICE_LOG_COMPRESS", |v| v.parse().ok()) {
+                    file.compress = compress;
+                }
+                LogOutput::File(file)
+            }
+            _ => LogOutput::Stderr,
+        };
+        let mut rate_limit = RateLimitConfig::default();
+        if let Some(burst) = env_parse_early("USERVICE_LOG_RATE_LIMIT", |v| v.parse().ok()) {
+            rate_limit.burst = burst;
+        }
+        if let Some(secs) = env_parse_early("USERVICE_LOG_RATE_WINDOW", |v| {
+            v.parse::<u64>().ok().filter(|secs| *secs > 0)
+        }) {
+            rate_limit.window = Duration::from_secs(secs);
+        }
+        if let Some(key) = env_parse_early("USERVICE_LOG_RATE_KEY", RateLimitKey::parse) {
+            rate_limit.key = key;
+        }
+        LogConfig {
+            format,
+            output,
+            rate_limit: (rate_limit.burst > 0).then_some(rate_limit),
+            #[cfg(feature = "trace")]
+            trace_ratio: tracing_enabled().then(|| {
+                env_parse_early("OTEL_TRACES_SAMPLER_ARG", |v| {
+                    v.parse().ok().filter(|ratio| (0.0..=1.0).contains(ratio))
+                })
+                .unwrap_or(1.0)
+            }),
+            version: env::var("USERVICE_VERSION").unwrap_or(defaults.version),
+            pod: env::var("POD_NAME").ok(),
+            ..defaults
+        }
+    }
+}
+
+/// Spans are exported unless `OTEL_TRACES_EXPORTER` is `none`, if it is `otlp` or an OTLP endpoint is set
+#[cfg(feature = "trace")]
+fn tracing_enabled() -> bool {
+    match env::var("OTEL_TRACES_EXPORTER").as_deref() {
+        Ok("none") => false,
+        Ok("otlp") => true,
+        _ => {
+            env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_some()
+                || env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some()
+        }
+    }
+}
+
+/// Collects the fields of an event into a JSON object
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        // Fields of bridged log records describe the record rather than the event
+        if !field.name().starts_with("log.") && !field.name().starts_with("otel.") {
+            self.0.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::from(format!("{:?}", value)));
+    }
+}
+
+/// Formats events as JSON lines with the service fields and the fields of their spans
+///
+/// Requires the span fields to be formatted by [JsonFields].
+pub struct JsonFormat {
+    config: LogConfig,
+}
+
+impl JsonFormat {
+    /// Create a [JsonFormat] adding the service fields of a [LogConfig]
+    pub fn new(config: &LogConfig) -> JsonFormat {
+        JsonFormat {
+            config: config.clone(),
+        }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut line = Map::new();
+        line.insert(String::from("timestamp"), Value::from(timestamp));
+        line.insert(
+            String::from("level"),
+            Value::from(metadata.level().as_str()),
+        );
+        line.insert(String::from("target"), Value::from(metadata.target()));
+        line.insert(
+            String::from("service"),
+            Value::from(self.config.service.as_str()),
+        );
+        line.insert(
+            String::from("version"),
+            Value::from(self.config.version.as_str()),
+        );
+        if let Some(pod) = &self.config.pod {
+            line.insert(String::from("pod"), Value::from(pod.as_str()));
+        }
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let fields = extensions
+                    .get::<FormattedFields<N>>()
+                    .and_then(|fields| serde_json::from_str::<Map<String, Value>>(fields).ok());
+                // Fields for OpenTelemetry describe the exported span rather than the request
+                line.extend(
+                    fields
+                        .into_iter()
+                        .flatten()
+                        .filter(|(name, _)| !name.starts_with("otel.")),
+                );
+            }
+        }
+        event.record(&mut JsonVisitor(&mut line));
+
+        let json = serde_json::to_string(&line).map_err(|_| fmt::Error)?;
+        writeln!(writer, "{}", json)
+    }
+}
+
+/// Environment filter from `RUST_LOG` defaulting to `info`
+fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// The filter in force and when it reverts to the default
+struct FilterState {
+    filter: String,
+    expires: Option<Instant>,
+    /// Incremented on each change so an expiry only reverts the change that set it
+    generation: u64,
+}
+
+/// Handle to change the log filter at runtime
+///
+/// Filters use the `RUST_LOG` syntax eg `info,rustyhello=debug,warp=warn`.
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+    default: String,
+    state: Arc<Mutex<FilterState>>,
+}
+
+impl LogLevel {
+    /// Create a [LogLevel] and the filter layer it controls, starting from and reverting to `default`
+    pub fn new(default: EnvFilter) -> (reload::Layer<EnvFilter, Registry>, LogLevel) {
+        let default_filter = default.to_string();
+        let (layer, handle) = reload::Layer::new(default);
+        let level = LogLevel {
+            handle,
+            state: Arc::new(Mutex::new(FilterState {
+                filter: default_filter.clone(),
+                expires: None,
+                generation: 0,
+            })),
+            default: default_filter,
+        };
+        (layer, level)
+    }
+
+    /// The filter in force
+    pub fn filter(&self) -> String {
+        self.state.lock().unwrap().filter.clone()
+    }
+
+    /// The filter reverted to
+    pub fn default_filter(&self) -> &str {
+        &self.default
+    }
+
+    /// Time until the filter in force reverts to the default, if it does
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .unwrap()
+            .expires
+            .map(|expires| expires.saturating_duration_since(Instant::now()))
+    }
+
+    /// Change the filter, reverting to the default after the time to live if one is given
+    ///
+    /// Reverting needs a tokio runtime, without one the filter is kept.
+    pub fn set(&self, filter: &str, ttl: Option<Duration>) -> Result<(), String> {
+        let parsed = EnvFilter::try_new(filter).map_err(|e| e.to_string())?;
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            state.filter = filter.to_string();
+            state.expires = ttl.map(|ttl| Instant::now() + ttl);
+            state.generation += 1;
+            state.generation
+        };
+        self.handle.reload(parsed).map_err(|e| e.to_string())?;
+        // Let records of the log crate through up to the new level
+        log::set_max_level(LevelFilter::current().as_log());
+        info!(filter, ttl = ?ttl, "Log filter changed");
+
+        if let Some(ttl) = ttl {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    let level = self.clone();
+                    runtime.spawn(async move {
+                        tokio::time::sleep(ttl).await;
+                        level.revert(generation);
+                    });
+                }
+                Err(_) => warn!("No runtime to revert the log filter"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Revert to the default filter
+    pub fn reset(&self) -> Result<(), String> {
+        self.set(&self.default, None)
+    }
+
+    /// Revert to the default filter if the filter has not changed since `generation`
+    fn revert(&self, generation: u64) {
+        if self.state.lock().unwrap().generation != generation {
+            return;
+        }
+        if let Err(e) = self.reset() {
+            warn!("Could not revert the log filter: {}", e);
+        }
+    }
+}
+
+static LOG_LEVEL: OnceLock<LogLevel> = OnceLock::new();
+
+/// The [LogLevel] of the subscriber installed by [init]
+pub fn log_level() -> Option<LogLevel> {
+    LOG_LEVEL.get().cloned()
+}
+
+static RATE_LIMIT: OnceLock<RateLimit> = OnceLock::new();
+
+/// The [RateLimit] of the subscriber installed by [init], if rate limiting
+pub fn rate_limit() -> Option<RateLimit> {
+    RATE_LIMIT.get().cloned()
+}
+
+/// Initialise the tracing subscriber writing to its output, bridging records of the `log` crate into it
+///
+/// A log file that cannot be opened is reported and stderr written to instead.
+/// The filter of the [LogLevel] only applies to the log output, so traces are recorded whatever the log level.
+pub fn init(config: &LogConfig) {
+    let (filter, level) = LogLevel::new(env_filter());
+    let (layer, rate_limit) = log_layer(config, filter);
+    if let Some(rate_limit) = rate_limit {
+        RATE_LIMIT.set(rate_limit).ok();
+    }
+    let subscriber = tracing_subscriber::registry().with(layer);
+    #[cfg(feature = "trace")]
+    let subscriber = subscriber.with(config.trace_ratio.map(otlptrace::layer));
+    subscriber.init();
+    LOG_LEVEL.set(level).ok();
+}
+
+/// The layer writing events to the output of the config, rate limited and filtered by the filter of a [LogLevel]
+pub(crate) fn log_layer(
+    config: &LogConfig,
+    filter: reload::Layer<EnvFilter, Registry>,
+) -> (impl Layer<Registry>, Option<RateLimit>) {
+    let file = match &config.output {
+        LogOutput::Stderr => None,
+        LogOutput::File(file) => LogFile::open(file)
+            .map_err(|e| eprintln!("Could not open log file {}: {}", file.path.display(), e))
+            .ok(),
+    };
+    let ansi = file.is_none() && io::stderr().is_terminal();
+    let writer = match file {
+        Some(file) => BoxMakeWriter::new(file),
+        None => BoxMakeWriter::new(io::stderr),
+    };
+    let (text, json) = match config.format {
+        LogFormat::Text => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(writer)
+                    .with_ansi(ansi),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(writer)
+                    .fmt_fields(JsonFields::new())
+                    .event_format(JsonFormat::new(config)),
+            ),
+        ),
+    };
+    let rate_limit = config.rate_limit.as_ref().map(RateLimit::new);
+    // Rate limiting inside the level filter only counts the events that would be written
+    let layer = Layer::and_then(text, json)
+        .with_filter(rate_limit.clone())
+        .with_filter(filter);
+    (layer, rate_limit)
+}
+
+/// Trace id of the request being handled, from its trace context or `traceparent` header
+pub fn trace_id(headers: &HeaderMap) -> Option<String> {
+    #[cfg(feature = "trace")]
+    if let Some(trace_id) = otlptrace::trace_id(&Span::current()) {
+        return Some(trace_id);
+    }
+    headers
+        .get("traceparent")
+        .and_then(|value| value.to_str().ok())
+        .and_then(traceparent_trace_id)
+        .map(String::from)
+}
+
+/// Request id from the headers of a request, generated if there is none
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(String::from)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+}
+
+/// Connection of a request handled by [serve], available to filters through `warp::ext`
+#[derive(Clone, Copy, Debug)]
+pub struct Connection {
+    /// Address of the client
+    pub remote_addr: SocketAddr,
+    /// Version of the http protocol of the request
+    pub version: Version,
+}
+
+/// Serve a filter on a port until shutdown, handling each request in a `request` span
+pub fn serve<F, R>(
+    filter: F,
+    port: u16,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> impl Future<Output = ()> + Send
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let make_service = make_service_fn(move |stream: &AddrStream| {
+        let remote_addr = stream.remote_addr();
+        let service = warp::service(filter.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(
+                move |mut request: warp::http::Request<warp::hyper::Body>| {
+                    let request_id = request_id(request.headers());
+                    let connection = Connection {
+                        remote_addr,
+                        version: request.version(),
+                    };
+                    request.extensions_mut().insert(connection);
+                    let span = info_span!(
+                        "request",
+                        method = %request.method(),
+                        path = request.uri().path(),
+                        route = field::Empty,
+                        request_id = request_id.as_str(),
+                        trace_id = field::Empty,
+                        status = field::Empty,
+                        latency_ms = field::Empty,
+                        otel.kind = field::Empty,
+                        otel.name = field::Empty,
+                        otel.status_code = field::Empty,
+                    );
+                    #[cfg(feature = "trace")]
+                    otlptrace::server_span(&span, request.headers());
+                    if let Some(trace_id) = span.in_scope(|| trace_id(request.headers())) {
+                        span.record("trace_id", trace_id);
+                    }
+                    let request_id = HeaderValue::from_str(&request_id).ok();
+                    if let Some(request_id) = &request_id {
+                        request
+                            .headers_mut()
+                            .insert(REQUEST_ID_HEADER, request_id.clone());
+                    }
+                    let start = Instant::now();
+                    let mut service = service.clone();
+                    async move {
+                        let mut response = service.call(request).await?;
+                        if let Some(request_id) = request_id {
+                            response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+                        }
+                        let span = Span::current();
+                        span.record("status", response.status().as_u16());
+                        if response.status().is_server_error() {
+                            span.record("otel.status_code", "error");
+                        }
+                        span.record("latency_ms", start.elapsed().as_secs_f64() * 1000.0);
+                        debug!("Request finished");
+                        Ok::<_, Infallible>(response)
+                    }
+                    .instrument(span)
+                },
+            ))
+        }
+    });
+
+    let server = Server::bind(&SocketAddr::from(([0, 0, 0, 0], port)))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown);
+    async move {
+        if let Err(e) = server.await {
+            error!("Server on {} failed: {}", port, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, warn};
+
+    /// Writer capturing the log lines of a test
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn subscriber(&self, config: &LogConfig) -> impl Subscriber + Send + Sync {
+            let captured = self.clone();
+            tracing_subscriber::fmt()
+                .with_writer(move || captured.clone())
+                .fmt_fields(JsonFields::new())
+                .event_format(JsonFormat::new(config))
+                .finish()
+        }
+
+        fn lines(&self) -> Vec<Value> {
+            let captured = self.0.lock().unwrap();
+            std::str::from_utf8(&captured)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        //! Test that a JSON line carries the event, service and span fields
+        let mut config = LogConfig::new("shop");
+        config.pod = Some(String::from("shop-1234"));
+        let captured = Captured::default();
+
+        tracing::subscriber::with_default(captured.subscriber(&config), || {
+            let span = info_span!("request", request_id = "abc", trace_id = field::Empty);
+            span.record("trace_id", "4bf92f3577b34da6a3ce929d0e0e4736");
+            let _entered = span.enter();
+            warn!(target: "shop::orders", order = 42, "Order \"{}\" paid", 42);
+        });
+
+        let line = &captured.lines()[0];
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "shop::orders");
+        assert_eq!(line["message"], "Order \"42\" paid");
+        assert_eq!(line["order"], 42);
+        assert_eq!(line["service"], "shop");
+        assert_eq!(line["pod"], "shop-1234");
+        assert_eq!(line["request_id"], "abc");
+        assert_eq!(line["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(LogFormat::parse("JSON"), Some(LogFormat::Json));
+    }
+
+    #[tokio::test]
+    async fn request_span_served() {
+        //! Test that requests are handled in a span with their request id which is returned
+        use warp::hyper::Client;
+
+        let captured = Captured::default();
+        let _default =
+            tracing::subscriber::set_default(captured.subscriber(&LogConfig::new("serve")));
+        let route = warp::path!("hello").map(|| {
+            info!("Saying hello");
+            "Hello"
+        });
+        let (channel, mut rx) = tokio::sync::mpsc::channel::<()>(1);
+        let server = tokio::spawn(serve(route, 7986, async move {
+            rx.recv().await;
+        }));
+
+        let request = warp::http::Request::get("http://localhost:7986/hello")
+            .header(REQUEST_ID_HEADER, "abc")
+            .body(warp::hyper::Body::empty())
+            .unwrap();
+        let resp = Client::new().request(request).await.unwrap();
+        assert_eq!(resp.headers()[REQUEST_ID_HEADER], "abc");
+        channel.send(()).await.unwrap();
+        server.await.unwrap();
+
+        let lines = captured.lines();
+        let hello = lines
+            .iter()
+            .find(|line| line["message"] == "Saying hello")
+            .expect("handler logged");
+        assert_eq!(hello["request_id"], "abc");
+        assert_eq!(hello["method"], "GET");
+        assert_eq!(hello["path"], "/hello");
+    }
+}
@@ -6,9 +6,7 @@
 //!  * Extensible prometheus
 //!  * SIGTERM safe shutdown
 //!  * Minimal docker build
-//!
-//! Items still to be added:
-//!  * [ ] Standardised Logging
+//!  * Standardised text or JSON logging
 //!
 //! Optional Features:
 //!  * [ ] kafka consumer/producer
@@ -16,10 +14,10 @@
 #![warn(missing_docs)]
 
 use clap::{App, Arg};
-use env_logger::Env;
-use log::{info};
+use tracing::{info};
 
-use rustyhello::{UServiceConfig, start};
+use rustyhello::logging::{self, LogConfig};
+use rustyhello::{buildinfo, UServiceConfig, start};
 
 fn main() {
     //! Capture CLI definition and call appropriate actions
@@ -86,7 +84,7 @@ fn main() {
 
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level app
-    if let Some(ref matches) = matches.subcommand_matches("test") {
+    if let Some(matches) = matches.subcommand_matches("test") {
         // "$ myapp test" was run
         if matches.is_present("debug") {
             // "$ myapp test -d" was run
@@ -96,15 +94,17 @@ fn main() {
         }
     }
 
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    logging::init(&LogConfig::from_env("simple"));
 
 
     match matches.subcommand() {
         Some(("version", _version_matches)) => {
-            const NAME: &str = env!("CARGO_PKG_NAME");
-            println!("Name: {}", NAME);
-            const VERSION: &str = env!("CARGO_PKG_VERSION");
-            println!("Version: {}", VERSION);
+            println!("Name: {}", buildinfo::NAME);
+            println!("Version: {}", buildinfo::VERSION);
+            println!("Git commit: {}", buildinfo::GIT_COMMIT);
+            println!("Build timestamp: {}", buildinfo::BUILD_TIMESTAMP);
+            println!("Rustc: {}", buildinfo::RUSTC_VERSION);
+            println!("Features: {}", buildinfo::FEATURES);
         }
         Some(("parse", validate_matches)) => {
             println!("parse and validate {:?}", validate_matches);
@@ -112,9 +112,7 @@ fn main() {
         Some(("start", _start_matches)) => {
             info!("Calling start");
 
-            start(&UServiceConfig {
-                name: String::from("simple"),
-            });
+            start(&UServiceConfig::from_env("simple"));
         }
         Some(("dev", _dev_matches)) => {
             println!("DEV system");
@@ -0,0 +1,600 @@
+//! Prometheus metrics owned by a [UService](crate::UService)
+//!
+//! Each [UService](crate::UService) owns a [Metrics] with its own [Registry] so that multiple services can run in one process.
+//! Components are given a handle to the [Metrics] to create their counters, gauges and histograms.
+//!
+//! Every series carries const labels for the service name and version, and the pod and kubernetes namespace when known.
+//! Metrics created through [Metrics] are named under the configured namespace and subsystem
+//! and creating a metric that already exists returns the existing metric, or an error if it was created with different help, labels or buckets.
+//!
+//! The buckets of the built-in latency histograms are set by [Buckets] in the [MetricsConfig].
+
+use crate::cardinality::{parse_limits, CardinalityLimits, Guarded};
+use crate::env_parse;
+use crate::httpmetrics::{HttpCollectors, HttpMetrics};
+use crate::openmetrics::Exemplars;
+#[cfg(feature = "hdr")]
+use crate::percentiles::Percentiles;
+use prometheus::core::{Collector, MetricVec, MetricVecBuilder};
+use prometheus::{
+    exponential_buckets, linear_buckets, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec,
+    IntCounter, IntCounterVec, Opts, Registry, DEFAULT_BUCKETS,
+};
+use serde::Serialize;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+/// Buckets of a latency histogram in seconds
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Buckets {
+    /// The Prometheus client default buckets from 5ms to 10s
+    Prometheus,
+    /// The OpenTelemetry HTTP server duration buckets from 5ms to 10s
+    Http,
+    /// `count` buckets starting at `start` each `width` apart
+    Linear {
+        start: f64,
+        width: f64,
+        count: usize,
+    },
+    /// `count` buckets starting at `start` each `factor` times the previous
+    Exponential {
+        start: f64,
+        factor: f64,
+        count: usize,
+    },
+    /// Explicit upper bounds
+    Custom(Vec<f64>),
+}
+
+impl Buckets {
+    /// Parse [Buckets] from a preset or a list of upper bounds
+    ///
+    ///  * `prometheus` or `http` for the presets
+    ///  * `linear:start,width,count` eg `linear:0.002,0.002,10`
+    ///  * `exponential:start,factor,count` eg `exponential:0.002,2,8`
+    ///  * a comma separated list of upper bounds eg `0.002,0.005,0.01`
+    pub fn parse(value: &str) -> Option<Buckets> {
+        let value = value.trim();
+        let buckets = match value.split_once(':') {
+            None if value.eq_ignore_ascii_case("prometheus") => Buckets::Prometheus,
+            None if value.eq_ignore_ascii_case("http") => Buckets::Http,
+            None => Buckets::Custom(
+                value
+                    .split(',')
+                    .map(|bound| bound.trim().parse().ok())
+                    .collect::<Option<_>>()?,
+            ),
+            Some((kind, args)) => {
+                let args: Vec<_> = args.split(',').map(str::trim).collect();
+                if args.len() != 3 {
+                    return None;
+                }
+                let start = args[0].parse().ok()?;
+                let step = args[1].parse().ok()?;
+                let count = args[2].parse().ok()?;
+                match kind.trim() {
+                    "linear" => Buckets::Linear {
+                        start,
+                        width: step,
+                        count,
+                    },
+                    "exponential" => Buckets::Exponential {
+                        start,
+                        factor: step,
+                        count,
+                    },
+                    _ => return None,
+                }
+            }
+        };
+        buckets.bounds().ok().map(|_| buckets)
+    }
+
+    /// Upper bounds of the buckets, failing if they are not increasing
+    pub fn bounds(&self) -> prometheus::Result<Vec<f64>> {
+        let bounds = match self {
+            Buckets::Prometheus => DEFAULT_BUCKETS.to_vec(),
+            Buckets::Http => vec![
+                0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+            ],
+            Buckets::Linear {
+                start,
+                width,
+                count,
+            } => linear_buckets(*start, *width, *count)?,
+            Buckets::Exponential {
+                start,
+                factor,
+                count,
+            } => exponential_buckets(*start, *factor, *count)?,
+            Buckets::Custom(bounds) => bounds.clone(),
+        };
+        if bounds.is_empty() || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(prometheus::Error::Msg(format!(
+                "buckets must be increasing: {:?}",
+                bounds
+            )));
+        }
+        Ok(bounds)
+    }
+}
+
+/// Configuration of the [Metrics] of a service
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MetricsConfig {
+    /// Namespace prefixed to the names of metrics created through [Metrics]
+    pub namespace: Option<String>,
+    /// Subsystem prefixed to the names of metrics created through [Metrics], after the namespace
+    pub subsystem: Option<String>,
+    /// Version of the service added as the `version` label
+    pub version: String,
+    /// Name of the pod added as the `pod` label
+    pub pod: Option<String>,
+    /// Kubernetes namespace added as the `namespace` label
+    pub kubernetes_namespace: Option<String>,
+    /// Buckets of the built-in latency histograms
+    pub latency_buckets: Buckets,
+    /// Record latencies in high resolution histograms for exact percentiles, requires the `hdr` feature
+    pub latency_percentiles: bool,
+    /// Window for which responses of the metrics endpoint are reused, zero to encode on every scrape
+    pub scrape_cache: Duration,
+    /// Limits of the number of label sets of vector metrics by full name, see [cardinality](crate::cardinality)
+    pub cardinality_limits: BTreeMap<String, usize>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> MetricsConfig {
+        MetricsConfig {
+            namespace: None,
+            subsystem: None,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pod: None,
+            kubernetes_namespace: None,
+            latency_buckets: Buckets::Http,
+            latency_percentiles: false,
+            scrape_cache: Duration::ZERO,
+            cardinality_limits: BTreeMap::new(),
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// Create a [MetricsConfig] from defaults overridden by environment variables
+    ///
+    ///  * `USERVICE_METRICS_NAMESPACE` and `USERVICE_METRICS_SUBSYSTEM` for metric names
+    ///  * `USERVICE_VERSION` for the version label
+    ///  * `POD_NAME` and `POD_NAMESPACE` as set from the kubernetes downward API
+    ///  * `USERVICE_LATENCY_BUCKETS` for the latency histogram [Buckets] as accepted by [Buckets::parse]
+    ///  * `USERVICE_LATENCY_PERCENTILES` set to `true` to record latencies in high resolution histograms
+    ///  * `USERVICE_METRICS_CACHE_MS` for the milliseconds responses of the metrics endpoint are reused
+    ///  * `USERVICE_CARDINALITY_LIMITS` for label set limits as `name=limit,name=limit`
+    pub fn from_env() -> MetricsConfig {
+        let defaults = MetricsConfig::default();
+        MetricsConfig {
+            namespace: env::var("USERVICE_METRICS_NAMESPACE").ok(),
+            subsystem: env::var("USERVICE_METRICS_SUBSYSTEM").ok(),
+            version: env::var("USERVICE_VERSION").unwrap_or(defaults.version),
+            pod: env::var("POD_NAME").ok(),
+            kubernetes_namespace: env::var("POD_NAMESPACE").ok(),
+            latency_buckets: env_parse("USERVICE_LATENCY_BUCKETS", Buckets::parse)
+                .unwrap_or(defaults.latency_buckets),
+            latency_percentiles: env_parse("USERVICE_LATENCY_PERCENTILES", |v| v.parse().ok())
+                .unwrap_or(defaults.latency_percentiles),
+            scrape_cache: env_parse("USERVICE_METRICS_CACHE_MS", |v| v.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.scrape_cache),
+            cardinality_limits: env_parse("USERVICE_CARDINALITY_LIMITS", parse_limits)
+                .unwrap_or(defaults.cardinality_limits),
+        }
+    }
+}
+
+/// Names of the const labels [Metrics] may add to every series
+pub const CONST_LABELS: &[&str] = &["service", "version", "pod", "namespace"];
+
+/// Handle to the metrics of a [UService](crate::UService)
+///
+/// Cloning the handle shares the underlying [Registry].
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    env: String,
+    namespace: Option<String>,
+    subsystem: Option<String>,
+    http: HttpCollectors,
+    scrape_cache: Duration,
+    cardinality: CardinalityLimits,
+    /// Metrics created through [Metrics] by full name
+    created: Arc<Mutex<HashMap<String, Created>>>,
+}
+
+/// What a metric was requested with, so a request for the same name with a different definition is an error
+#[derive(Debug, PartialEq)]
+struct Definition {
+    help: String,
+    labels: Vec<String>,
+    /// Bucket bounds of histograms
+    buckets: Option<Vec<f64>>,
+}
+
+impl Definition {
+    fn new(opts: &Opts, labels: &[&str], buckets: Option<&[f64]>) -> Definition {
+        Definition {
+            help: opts.help.clone(),
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+            buckets: buckets.map(<[f64]>::to_vec),
+        }
+    }
+}
+
+/// A metric created through [Metrics]
+struct Created {
+    metric: Box<dyn Any + Send>,
+    definition: Definition,
+}
+
+impl Metrics {
+    /// Create [Metrics] whose series all carry a `service` label with the name of the service
+    pub fn new(service: &str, env: &str) -> Metrics {
+        Metrics::with_config(service, env, &MetricsConfig::default())
+    }
+
+    /// Create [Metrics] with the const labels and naming from a [MetricsConfig]
+    pub fn with_config(service: &str, env: &str, config: &MetricsConfig) -> Metrics {
+        let mut labels = HashMap::new();
+        labels.insert(String::from("service"), service.to_string());
+        labels.insert(String::from("version"), config.version.clone());
+        if let Some(pod) = &config.pod {
+            labels.insert(String::from("pod"), pod.clone());
+        }
+        if let Some(namespace) = &config.kubernetes_namespace {
+            labels.insert(String::from("namespace"), namespace.clone());
+        }
+        let registry = Registry::new_custom(None, Some(labels)).expect("registry can be created");
+        let buckets = config.latency_buckets.bounds().unwrap_or_else(|e| {
+            warn!("Using default latency buckets: {}", e);
+            DEFAULT_BUCKETS.to_vec()
+        });
+        let cardinality = CardinalityLimits::register(&registry, &config.cardinality_limits)
+            .expect("collector can be registered");
+        let http = HttpCollectors::register(&registry, &buckets, &cardinality)
+            .expect("collector can be registered");
+        #[cfg(feature = "hdr")]
+        let http = HttpCollectors {
+            percentiles: config.latency_percentiles.then(|| {
+                let percentiles = Percentiles::new(
+                    "http_server_request_duration_quantiles_seconds",
+                    "Exact quantiles of the duration of HTTP server requests",
+                    "http_route",
+                );
+                registry
+                    .register(Box::new(percentiles.clone()))
+                    .expect("collector can be registered");
+                percentiles
+            }),
+            ..http
+        };
+        #[cfg(not(feature = "hdr"))]
+        if config.latency_percentiles {
+            warn!("Latency percentiles require the hdr feature");
+        }
+
+        Metrics {
+            registry,
+            env: env.to_string(),
+            namespace: config.namespace.clone(),
+            subsystem: config.subsystem.clone(),
+            http,
+            scrape_cache: config.scrape_cache,
+            cardinality,
+            created: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The [Registry] holding the metrics of the service
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Window for which responses of the metrics endpoint are reused
+    pub fn scrape_cache(&self) -> Duration {
+        self.scrape_cache
+    }
+
+    /// The [Exemplars] of the histograms of the service
+    pub fn exemplars(&self) -> &Exemplars {
+        &self.http.exemplars
+    }
+
+    /// The high resolution latency histograms of http requests by route, when enabled in the [MetricsConfig]
+    #[cfg(feature = "hdr")]
+    pub fn percentiles(&self) -> Option<&Percentiles> {
+        self.http.percentiles.as_ref()
+    }
+
+    /// Register a collector, logging rather than failing if it cannot be registered
+    pub fn register<C: Collector + Clone + 'static>(&self, collector: &C) {
+        if let Err(e) = self.registry.register(Box::new(collector.clone())) {
+            warn!("Collector could not be registered: {}", e);
+        }
+    }
+
+    /// [HttpMetrics] for the named http server with its route templates
+    pub fn http<S: AsRef<str>>(&self, server: &'static str, routes: &[S]) -> HttpMetrics {
+        HttpMetrics::new(&self.http, &self.env, server, routes)
+    }
+
+    /// [Opts] for a metric named under the namespace and subsystem
+    fn opts(&self, name: &str, help: &str) -> Opts {
+        let mut opts = Opts::new(name, help);
+        if let Some(namespace) = &self.namespace {
+            opts = opts.namespace(namespace.as_str());
+        }
+        if let Some(subsystem) = &self.subsystem {
+            opts = opts.subsystem(subsystem.as_str());
+        }
+        opts
+    }
+
+    /// Guard a vector metric with the limit configured for its name
+    fn guard<T: MetricVecBuilder>(&self, vec: MetricVec<T>, opts: &Opts) -> Guarded<MetricVec<T>> {
+        self.cardinality.guard(vec, &opts.fq_name(), None)
+    }
+
+    /// Return the metric already created with the name of `opts` or create and register a new one
+    ///
+    /// It is an error if the metric was created as a different type or with different help, labels or buckets.
+    fn get_or_create<M, F>(
+        &self,
+        opts: &Opts,
+        definition: Definition,
+        create: F,
+    ) -> prometheus::Result<M>
+    where
+        M: Collector + Clone + Send + 'static,
+        F: FnOnce() -> prometheus::Result<M>,
+    {
+        let name = opts.fq_name();
+        let mut created = self.created.lock().unwrap();
+        if let Some(existing) = created.get(&name) {
+            let metric = existing.metric.downcast_ref::<M>().ok_or_else(|| {
+                prometheus::Error::Msg(format!("{} already created as a different type", name))
+            })?;
+            if existing.definition != definition {
+                return Err(prometheus::Error::Msg(format!(
+                    "{} already created as {:?}, not {:?}",
+                    name, existing.definition, definition
+                )));
+            }
+            return Ok(metric.clone());
+        }
+
+        let metric = create()?;
+        self.registry.register(Box::new(metric.clone()))?;
+        created.insert(
+            name,
+            Created {
+                metric: Box::new(metric.clone()),
+                definition,
+            },
+        );
+        Ok(metric)
+    }
+
+    /// Create or get a counter
+    pub fn counter(&self, name: &str, help: &str) -> prometheus::Result<IntCounter> {
+        let opts = self.opts(name, help);
+        let definition = Definition::new(&opts, &[], None);
+        self.get_or_create(&opts, definition, || IntCounter::with_opts(opts.clone()))
+    }
+
+    /// Create or get a counter with labels
+    pub fn counter_vec(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+    ) -> prometheus::Result<Guarded<IntCounterVec>> {
+        let opts = self.opts(name, help);
+        let definition = Definition::new(&opts, labels, None);
+        self.get_or_create(&opts, definition, || {
+            IntCounterVec::new(opts.clone(), labels).map(|vec| self.guard(vec, &opts))
+        })
+    }
+
+    /// Create or get a gauge
+    pub fn gauge(&self, name: &str, help: &str) -> prometheus::Result<Gauge> {
+        let opts = self.opts(name, help);
+        let definition = Definition::new(&opts, &[], None);
+        self.get_or_create(&opts, definition, || Gauge::with_opts(opts.clone()))
+    }
+
+    /// Create or get a gauge with labels
+    pub fn gauge_vec(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+    ) -> prometheus::Result<Guarded<GaugeVec>> {
+        let opts = self.opts(name, help);
+        let definition = Definition::new(&opts, labels, None);
+        self.get_or_create(&opts, definition, || {
+            GaugeVec::new(opts.clone(), labels).map(|vec| self.guard(vec, &opts))
+        })
+    }
+
+    /// Create or get a histogram, using the default buckets if none are given
+    pub fn histogram(
+        &self,
+        name: &str,
+        help: &str,
+        buckets: Option<Vec<f64>>,
+    ) -> prometheus::Result<Histogram> {
+        let opts = self.opts(name, help);
+        let bounds = buckets.as_deref().unwrap_or(DEFAULT_BUCKETS);
+        let definition = Definition::new(&opts, &[], Some(bounds));
+        self.get_or_create(&opts, definition, || {
+            Histogram::with_opts(histogram_opts(opts.clone(), buckets))
+        })
+    }
+
+    /// Create or get a histogram with labels, using the default buckets if none are given
+    pub fn histogram_vec(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+        buckets: Option<Vec<f64>>,
+    ) -> prometheus::Result<Guarded<HistogramVec>> {
+        let opts = self.opts(name, help);
+        let bounds = buckets.as_deref().unwrap_or(DEFAULT_BUCKETS);
+        let definition = Definition::new(&opts, labels, Some(bounds));
+        self.get_or_create(&opts, definition, || {
+            HistogramVec::new(histogram_opts(opts.clone(), buckets), labels)
+                .map(|vec| self.guard(vec, &opts))
+        })
+    }
+}
+
+fn histogram_opts(opts: Opts, buckets: Option<Vec<f64>>) -> HistogramOpts {
+    let opts = HistogramOpts::from(opts);
+    match buckets {
+        Some(buckets) => opts.buckets(buckets),
+        None => opts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_created_once() {
+        //! Test that creating a metric twice returns the same metric and a different type or definition is an error
+        let metrics = Metrics::new("test", "test");
+
+        let first = metrics.counter("jobs", "Jobs run").unwrap();
+        let second = metrics.counter("jobs", "Jobs run").unwrap();
+        first.inc();
+        assert_eq!(second.get(), 1);
+
+        assert!(metrics.gauge("jobs", "Jobs run").is_err());
+        assert!(metrics.counter("jobs", "Jobs started").is_err());
+
+        let by_queue = metrics
+            .counter_vec("queued", "Jobs queued", &["queue"])
+            .unwrap();
+        assert!(metrics
+            .counter_vec("queued", "Jobs queued", &["queue", "priority"])
+            .is_err());
+        metrics
+            .histogram("job_seconds", "Job duration", None)
+            .unwrap();
+        assert!(metrics
+            .histogram(
+                "job_seconds",
+                "Job duration",
+                Some(DEFAULT_BUCKETS.to_vec())
+            )
+            .is_ok());
+        assert!(metrics
+            .histogram("job_seconds", "Job duration", Some(vec![1.0, 10.0]))
+            .is_err());
+        by_queue.with_label_values(&["default"]).inc();
+        let families = metrics.registry().gather();
+        assert_eq!(
+            families.iter().filter(|f| f.get_name() == "jobs").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn metrics_named_and_labelled() {
+        //! Test that metrics are named under the namespace and subsystem and carry the const labels
+        let config = MetricsConfig {
+            namespace: Some(String::from("shop")),
+            subsystem: Some(String::from("orders")),
+            version: String::from("1.2.3"),
+            pod: Some(String::from("shop-1234")),
+            kubernetes_namespace: Some(String::from("prod")),
+            ..MetricsConfig::default()
+        };
+        let metrics = Metrics::with_config("shop", "test", &config);
+        let histogram = metrics
+            .histogram_vec(
+                "latency_seconds",
+                "Latency",
+                &["step"],
+                Some(vec![0.1, 1.0]),
+            )
+            .unwrap();
+        histogram.with_label_values(&["pay"]).observe(0.5);
+
+        let families = metrics.registry().gather();
+        let family = families
+            .iter()
+            .find(|f| f.get_name() == "shop_orders_latency_seconds")
+            .expect("histogram is namespaced");
+        let labels: HashMap<_, _> = family.get_metric()[0]
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name(), l.get_value()))
+            .collect();
+        assert_eq!(labels["service"], "shop");
+        assert_eq!(labels["version"], "1.2.3");
+        assert_eq!(labels["pod"], "shop-1234");
+        assert_eq!(labels["namespace"], "prod");
+        assert_eq!(labels["step"], "pay");
+    }
+
+    #[test]
+    fn latency_buckets() {
+        //! Test that bucket presets and lists are parsed and invalid buckets rejected
+        assert_eq!(Buckets::parse("http"), Some(Buckets::Http));
+        assert_eq!(
+            Buckets::parse("linear:0.002,0.002,3")
+                .unwrap()
+                .bounds()
+                .unwrap(),
+            vec![0.002, 0.004, 0.006]
+        );
+        assert_eq!(
+            Buckets::parse("exponential:0.002,2,3")
+                .unwrap()
+                .bounds()
+                .unwrap(),
+            vec![0.002, 0.004, 0.008]
+        );
+        assert_eq!(
+            Buckets::parse("0.002, 0.2"),
+            Some(Buckets::Custom(vec![0.002, 0.2]))
+        );
+        assert_eq!(Buckets::parse("0.2,0.002"), None);
+        assert_eq!(Buckets::parse("linear:0.002,0.002"), None);
+        assert_eq!(Buckets::parse("cubic:1,2,3"), None);
+
+        let config = MetricsConfig {
+            latency_buckets: Buckets::Custom(vec![0.002, 0.2]),
+            ..MetricsConfig::default()
+        };
+        let metrics = Metrics::with_config("test", "test", &config);
+        metrics
+            .http
+            .duration
+            .with_label_values(&["test", "test", "GET", "/", "200"])
+            .observe(0.1);
+        let families = metrics.registry().gather();
+        let family = families
+            .iter()
+            .find(|f| f.get_name() == "http_server_request_duration_seconds")
+            .unwrap();
+        let buckets = family.get_metric()[0].get_histogram().get_bucket();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[1].get_upper_bound(), 0.2);
+    }
+}
@@ -0,0 +1,347 @@
+//! [OpenMetrics](https://openmetrics.io) exposition with exemplars
+//!
+//! The prometheus crate only encodes the Prometheus text format, so [encode] writes gathered metric families in the OpenMetrics text format.
+//! Histogram buckets can carry an [Exemplar] recorded in [Exemplars], linking a bucket to the trace of a request that landed in it.
+
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Content type of the OpenMetrics text format
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// A sample linked to the trace that produced it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    /// Id of the trace the sample was recorded in
+    pub trace_id: String,
+    /// Value of the sample
+    pub value: f64,
+    /// Time the sample was recorded
+    pub timestamp: SystemTime,
+}
+
+/// Exemplars of the series of a histogram
+#[derive(Default)]
+struct FamilyExemplars {
+    /// Names of the labels identifying a series
+    label_names: Vec<String>,
+    /// Most recent [Exemplar] for each bucket (including `+Inf`) by label values
+    series: HashMap<Vec<String>, Vec<Option<Exemplar>>>,
+}
+
+/// Store of the latest [Exemplar] of each histogram bucket
+///
+/// Cloning shares the store.
+#[derive(Clone, Default)]
+pub struct Exemplars {
+    families: Arc<Mutex<HashMap<String, FamilyExemplars>>>,
+}
+
+impl Exemplars {
+    /// Record an [Exemplar] for the bucket of `value` in the histogram series identified by name and labels
+    ///
+    /// `buckets` are the upper bounds of the histogram, excluding `+Inf`.
+    pub fn record(
+        &self,
+        histogram: &str,
+        labels: &[(&str, &str)],
+        buckets: &[f64],
+        value: f64,
+        trace_id: &str,
+    ) {
+        let bucket = buckets
+            .iter()
+            .position(|upper| value <= *upper)
+            .unwrap_or(buckets.len());
+
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(histogram.to_string()).or_default();
+        if family.label_names.is_empty() {
+            family.label_names = labels.iter().map(|(name, _)| name.to_string()).collect();
+        }
+        let values = labels.iter().map(|(_, value)| value.to_string()).collect();
+        let series = family
+            .series
+            .entry(values)
+            .or_insert_with(|| vec![None; buckets.len() + 1]);
+        if let Some(slot) = series.get_mut(bucket) {
+            *slot = Some(Exemplar {
+                trace_id: trace_id.to_string(),
+                value,
+                timestamp: SystemTime::now(),
+            });
+        }
+    }
+
+    /// Exemplars for each bucket of the histogram series of a gathered [Metric]
+    fn lookup(&self, histogram: &str, metric: &Metric) -> Option<Vec<Option<Exemplar>>> {
+        let families = self.families.lock().unwrap();
+        let family = families.get(histogram)?;
+        let values: Vec<String> = family
+            .label_names
+            .iter()
+            .map(|name| {
+                metric
+                    .get_label()
+                    .iter()
+                    .find(|label| label.get_name() == name)
+                    .map(|label| label.get_value().to_string())
+                    .unwrap_or_default()
+            })
+            .collect();
+        family.series.get(&values).cloned()
+    }
+}
+
+/// Extract the trace id from a W3C `traceparent` header
+pub fn traceparent_trace_id(traceparent: &str) -> Option<&str> {
+    let mut parts = traceparent.split('-');
+    let _version = parts.next()?;
+    let trace_id = parts.next()?;
+    if trace_id.len() == 32
+        && trace_id.bytes().all(|b| b.is_ascii_hexdigit())
+        && trace_id.bytes().any(|b| b != b'0')
+    {
+        Some(trace_id)
+    } else {
+        None
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        String::from("NaN")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_labels(
+    writer: &mut dyn Write,
+    metric: &Metric,
+    extra: Option<(&str, &str)>,
+) -> io::Result<()> {
+    let mut labels: Vec<(&str, &str)> = metric
+        .get_label()
+        .iter()
+        .map(|label| (label.get_name(), label.get_value()))
+        .collect();
+    labels.extend(extra);
+    if labels.is_empty() {
+        return Ok(());
+    }
+
+    writer.write_all(b"{")?;
+    for (i, (name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        write!(writer, "{}=\"{}\"", name, escape(value))?;
+    }
+    writer.write_all(b"}")
+}
+
+fn write_sample(
+    writer: &mut dyn Write,
+    name: &str,
+    suffix: &str,
+    metric: &Metric,
+    extra: Option<(&str, &str)>,
+    value: f64,
+    exemplar: Option<&Exemplar>,
+) -> io::Result<()> {
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(suffix.as_bytes())?;
+    write_labels(writer, metric, extra)?;
+    write!(writer, " {}", format_value(value))?;
+    if metric.get_timestamp_ms() != 0 {
+        write!(writer, " {}", metric.get_timestamp_ms() as f64 / 1000.0)?;
+    }
+    if let Some(exemplar) = exemplar {
+        let timestamp = exemplar
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        write!(
+            writer,
+            " # {{trace_id=\"{}\"}} {} {:.3}",
+            escape(&exemplar.trace_id),
+            format_value(exemplar.value),
+            timestamp
+        )?;
+    }
+    writer.write_all(b"\n")
+}
+
+/// Encode metric families in the OpenMetrics text format including the terminating `# EOF`
+pub fn encode(
+    families: &[MetricFamily],
+    exemplars: &Exemplars,
+    writer: &mut dyn Write,
+) -> io::Result<()> {
+    for family in families {
+        let name = family.get_name();
+        let (family_name, kind) = match family.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+
+        writeln!(writer, "# TYPE {} {}", family_name, kind)?;
+        if !family.get_help().is_empty() {
+            writeln!(
+                writer,
+                "# HELP {} {}",
+                family_name,
+                escape(family.get_help())
+            )?;
+        }
+
+        for metric in family.get_metric() {
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let value = metric.get_counter().get_value();
+                    write_sample(writer, family_name, "_total", metric, None, value, None)?;
+                }
+                MetricType::GAUGE => {
+                    let value = metric.get_gauge().get_value();
+                    write_sample(writer, name, "", metric, None, value, None)?;
+                }
+                MetricType::UNTYPED => {
+                    let value = metric.get_untyped().get_value();
+                    write_sample(writer, name, "", metric, None, value, None)?;
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let buckets = exemplars.lookup(name, metric).unwrap_or_default();
+                    let mut inf_seen = false;
+                    for (i, bucket) in histogram.get_bucket().iter().enumerate() {
+                        let upper = bucket.get_upper_bound();
+                        inf_seen |= upper.is_infinite() && upper > 0.0;
+                        write_sample(
+                            writer,
+                            name,
+                            "_bucket",
+                            metric,
+                            Some(("le", &format_value(upper))),
+                            bucket.get_cumulative_count() as f64,
+                            buckets.get(i).and_then(Option::as_ref),
+                        )?;
+                    }
+                    if !inf_seen {
+                        write_sample(
+                            writer,
+                            name,
+                            "_bucket",
+                            metric,
+                            Some(("le", "+Inf")),
+                            histogram.get_sample_count() as f64,
+                            buckets.last().and_then(Option::as_ref),
+                        )?;
+                    }
+                    let sum = histogram.get_sample_sum();
+                    let count = histogram.get_sample_count() as f64;
+                    write_sample(writer, name, "_sum", metric, None, sum, None)?;
+                    write_sample(writer, name, "_count", metric, None, count, None)?;
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        write_sample(
+                            writer,
+                            name,
+                            "",
+                            metric,
+                            Some(("quantile", &quantile.get_quantile().to_string())),
+                            quantile.get_value(),
+                            None,
+                        )?;
+                    }
+                    let sum = summary.get_sample_sum();
+                    let count = summary.get_sample_count() as f64;
+                    write_sample(writer, name, "_sum", metric, None, sum, None)?;
+                    write_sample(writer, name, "_count", metric, None, count, None)?;
+                }
+            }
+        }
+    }
+    writer.write_all(b"# EOF\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{HistogramOpts, HistogramVec, IntCounter, Registry};
+
+    #[test]
+    fn traceparent_parsing() {
+        //! Test that only valid trace ids are taken from traceparent headers
+        assert_eq!(
+            traceparent_trace_id("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        assert_eq!(
+            traceparent_trace_id("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            None
+        );
+        assert_eq!(traceparent_trace_id("garbage"), None);
+    }
+
+    #[test]
+    fn openmetrics_encoding() {
+        //! Test that counters, histograms and exemplars are encoded in the OpenMetrics format
+        let registry = Registry::new();
+        let counter = IntCounter::new("jobs", "Jobs \"run\"").unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("latency_seconds", "Latency").buckets(vec![0.1, 1.0]),
+            &["route"],
+        )
+        .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        counter.inc();
+        histogram.with_label_values(&["/a"]).observe(0.5);
+
+        let exemplars = Exemplars::default();
+        exemplars.record(
+            "latency_seconds",
+            &[("route", "/a")],
+            &[0.1, 1.0],
+            0.5,
+            "4bf92f3577b34da6a3ce929d0e0e4736",
+        );
+
+        let mut buffer = Vec::new();
+        encode(&registry.gather(), &exemplars, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert!(lines.contains(&"# TYPE jobs counter"));
+        assert!(lines.contains(&"# HELP jobs Jobs \\\"run\\\""));
+        assert!(lines.contains(&"jobs_total 1"));
+        assert!(lines.contains(&"latency_seconds_bucket{route=\"/a\",le=\"0.1\"} 0"));
+        let exemplar_line = lines
+            .iter()
+            .find(|line| line.starts_with("latency_seconds_bucket{route=\"/a\",le=\"1\"} 1 # "))
+            .expect("bucket has exemplar");
+        assert!(exemplar_line.contains("{trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.5 "));
+        assert!(lines.contains(&"latency_seconds_bucket{route=\"/a\",le=\"+Inf\"} 1"));
+        assert!(lines.contains(&"latency_seconds_count{route=\"/a\"} 1"));
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+}
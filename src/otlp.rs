@@ -0,0 +1,621 @@
+//! Export metrics over [OTLP](https://opentelemetry.io/docs/specs/otlp/) to an OpenTelemetry collector
+//!
+//! The registered metrics are gathered on an interval, converted to OTLP metrics with cumulative temporality
+//! and sent over gRPC or HTTP/protobuf, with a final export when the [UService](crate::UService) shuts down.
+//! The resource carries the service name, version, environment and pod.
+//!
+//! Configured by the standard `OTEL_*` environment variables and only available with the `otlp` feature.
+//! Only plain `http` endpoints are supported.
+//!
+//! The configuration and transport are shared with the span exporter of the `trace` feature.
+
+use crate::buildinfo::ServiceInfo;
+use crate::env_parse;
+use crate::metrics::Metrics;
+use crate::{spawn_component, HandleChannel, UServiceConfig};
+use opentelemetry_proto::tonic::collector::metrics::v1::metrics_service_client::MetricsServiceClient;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    metric, number_data_point, summary_data_point, AggregationTemporality, Gauge, Histogram,
+    HistogramDataPoint, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum, Summary,
+    SummaryDataPoint,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use prometheus::proto::{self, MetricFamily, MetricType};
+use prost::Message;
+use serde::Serialize;
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tonic::metadata::{AsciiMetadataValue, MetadataKey};
+use tonic::transport::{Channel, Endpoint};
+use tracing::{debug, info, warn};
+use warp::http::{Method, Request};
+use warp::hyper::{Body, Client};
+
+/// Transport protocol of the OTLP exporter
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Protocol {
+    Grpc,
+    HttpProtobuf,
+}
+
+impl Protocol {
+    /// Parse a protocol as named by `OTEL_EXPORTER_OTLP_PROTOCOL`
+    pub fn parse(value: &str) -> Option<Protocol> {
+        match value.trim() {
+            "grpc" => Some(Protocol::Grpc),
+            "http/protobuf" => Some(Protocol::HttpProtobuf),
+            _ => None,
+        }
+    }
+}
+
+/// Signal exported over OTLP, naming its environment variables and HTTP path
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    Metrics,
+    Traces,
+}
+
+impl Signal {
+    /// Name of the signal in `OTEL_EXPORTER_OTLP_<SIGNAL>_*` variables
+    fn env(self) -> &'static str {
+        match self {
+            Signal::Metrics => "METRICS",
+            Signal::Traces => "TRACES",
+        }
+    }
+
+    /// Path of the signal for HTTP
+    fn path(self) -> &'static str {
+        match self {
+            Signal::Metrics => "/v1/metrics",
+            Signal::Traces => "/v1/traces",
+        }
+    }
+}
+
+/// Configuration of an OTLP exporter
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OtlpConfig {
+    /// URL the signal is sent to, including its path such as `/v1/metrics` for HTTP
+    pub endpoint: String,
+    pub protocol: Protocol,
+    /// Time between exports
+    pub interval: Duration,
+    /// Maximum time for an export
+    pub timeout: Duration,
+    /// Headers sent with each export
+    pub headers: Vec<(String, String)>,
+    /// Attributes of the resource the signal is reported for
+    pub resource: Vec<(String, String)>,
+}
+
+impl OtlpConfig {
+    /// Create an [OtlpConfig] for a collector at an endpoint with resource attributes from the service configuration
+    ///
+    /// For HTTP the endpoint is the full URL of the signal path.
+    pub fn new(endpoint: &str, protocol: Protocol, config: &UServiceConfig) -> OtlpConfig {
+        let mut resource = vec![
+            (String::from("service.name"), config.name.clone()),
+            (
+                String::from("service.version"),
+                config.metrics.version.clone(),
+            ),
+            (String::from("deployment.environment"), config.env.clone()),
+        ];
+        if let Some(pod) = &config.metrics.pod {
+            resource.push((String::from("k8s.pod.name"), pod.clone()));
+        }
+        if let Some(namespace) = &config.metrics.kubernetes_namespace {
+            resource.push((String::from("k8s.namespace.name"), namespace.clone()));
+        }
+        OtlpConfig {
+            endpoint: endpoint.to_string(),
+            protocol,
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(30),
+            headers: Vec::new(),
+            resource,
+        }
+    }
+
+    /// Create an [OtlpConfig] for metrics from the `OTEL_*` environment variables, if an endpoint is set or `OTEL_METRICS_EXPORTER` is `otlp`
+    ///
+    ///  * `OTEL_METRICS_EXPORTER` set to `none` disables the exporter
+    ///  * `OTEL_EXPORTER_OTLP_METRICS_ENDPOINT` or `OTEL_EXPORTER_OTLP_ENDPOINT` for the collector
+    ///  * `OTEL_EXPORTER_OTLP_METRICS_PROTOCOL` or `OTEL_EXPORTER_OTLP_PROTOCOL` as `grpc` or `http/protobuf`
+    ///  * `OTEL_EXPORTER_OTLP_METRICS_HEADERS` or `OTEL_EXPORTER_OTLP_HEADERS` as `name=value` pairs separated by commas
+    ///  * `OTEL_METRIC_EXPORT_INTERVAL` and `OTEL_METRIC_EXPORT_TIMEOUT` in milliseconds
+    ///  * `OTEL_SERVICE_NAME` and `OTEL_RESOURCE_ATTRIBUTES` to override resource attributes
+    pub fn from_env(config: &UServiceConfig) -> Option<OtlpConfig> {
+        OtlpConfig::signal_from_env(Signal::Metrics, config)
+    }
+
+    /// Create an [OtlpConfig] for a signal from the `OTEL_*` environment variables
+    ///
+    /// Traces are configured as metrics are by [from_env](OtlpConfig::from_env) with `TRACES` in place of `METRICS`,
+    /// except the interval and timeout which are `OTEL_BSP_SCHEDULE_DELAY` and `OTEL_BSP_EXPORT_TIMEOUT`.
+    pub fn signal_from_env(signal: Signal, config: &UServiceConfig) -> Option<OtlpConfig> {
+        let exporter = env::var(format!("OTEL_{}_EXPORTER", signal.env())).ok();
+        let signal_endpoint =
+            env::var(format!("OTEL_EXPORTER_OTLP_{}_ENDPOINT", signal.env())).ok();
+        let endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok();
+        match exporter.as_deref() {
+            Some("none") => return None,
+            Some("otlp") => {}
+            _ if signal_endpoint.is_none() && endpoint.is_none() => return None,
+            _ => {}
+        }
+
+        let protocol = env_parse(
+            &format!("OTEL_EXPORTER_OTLP_{}_PROTOCOL", signal.env()),
+            Protocol::parse,
+        )
+        .or_else(|| env_parse("OTEL_EXPORTER_OTLP_PROTOCOL", Protocol::parse))
+        .unwrap_or(Protocol::HttpProtobuf);
+        let endpoint = match (signal_endpoint, endpoint, protocol) {
+            (Some(signal_endpoint), _, _) => signal_endpoint,
+            (None, Some(endpoint), Protocol::Grpc) => endpoint,
+            (None, Some(endpoint), Protocol::HttpProtobuf) => {
+                format!("{}{}", endpoint.trim_end_matches('/'), signal.path())
+            }
+            (None, None, Protocol::Grpc) => String::from("http://localhost:4317"),
+            (None, None, Protocol::HttpProtobuf) => {
+                format!("http://localhost:4318{}", signal.path())
+            }
+        };
+
+        let mut otlp = OtlpConfig::new(&endpoint, protocol, config);
+        let (interval, timeout) = match signal {
+            Signal::Metrics => ("OTEL_METRIC_EXPORT_INTERVAL", "OTEL_METRIC_EXPORT_TIMEOUT"),
+            Signal::Traces => {
+                otlp.interval = Duration::from_secs(5);
+                ("OTEL_BSP_SCHEDULE_DELAY", "OTEL_BSP_EXPORT_TIMEOUT")
+            }
+        };
+        if let Some(interval) = env_parse(interval, |v| v.parse().ok()) {
+            otlp.interval = Duration::from_millis(interval);
+        }
+        if let Some(timeout) = env_parse(timeout, |v| v.parse().ok()) {
+            otlp.timeout = Duration::from_millis(timeout);
+        }
+        otlp.headers = env_parse(
+            &format!("OTEL_EXPORTER_OTLP_{}_HEADERS", signal.env()),
+            parse_pairs,
+        )
+        .or_else(|| env_parse("OTEL_EXPORTER_OTLP_HEADERS", parse_pairs))
+        .unwrap_or_default();
+        let mut overrides = env_parse("OTEL_RESOURCE_ATTRIBUTES", parse_pairs).unwrap_or_default();
+        if let Ok(name) = env::var("OTEL_SERVICE_NAME") {
+            overrides.push((String::from("service.name"), name));
+        }
+        for (key, value) in overrides {
+            otlp.resource.retain(|(existing, _)| *existing != key);
+            otlp.resource.push((key, value));
+        }
+        Some(otlp)
+    }
+}
+
+/// Parse `name=value` pairs separated by commas
+fn parse_pairs(value: &str) -> Option<Vec<(String, String)>> {
+    value
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+pub(crate) fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+pub(crate) fn key_value(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+fn attributes(metric: &proto::Metric) -> Vec<KeyValue> {
+    metric
+        .get_label()
+        .iter()
+        .map(|label| key_value(label.get_name(), label.get_value()))
+        .collect()
+}
+
+/// Convert a gathered metric family to an OTLP metric with cumulative temporality since `start`
+fn convert(family: &MetricFamily, start: u64, now: u64) -> Metric {
+    let number = |metric: &proto::Metric, value: f64| NumberDataPoint {
+        attributes: attributes(metric),
+        start_time_unix_nano: start,
+        time_unix_nano: now,
+        value: Some(number_data_point::Value::AsDouble(value)),
+        ..Default::default()
+    };
+    let metrics = family.get_metric();
+
+    let data = match family.get_field_type() {
+        MetricType::COUNTER => metric::Data::Sum(Sum {
+            data_points: metrics
+                .iter()
+                .map(|m| number(m, m.get_counter().get_value()))
+                .collect(),
+            aggregation_temporality: AggregationTemporality::Cumulative as i32,
+            is_monotonic: true,
+        }),
+        MetricType::GAUGE => metric::Data::Gauge(Gauge {
+            data_points: metrics
+                .iter()
+                .map(|m| number(m, m.get_gauge().get_value()))
+                .collect(),
+        }),
+        MetricType::UNTYPED => metric::Data::Gauge(Gauge {
+            data_points: metrics
+                .iter()
+                .map(|m| number(m, m.get_untyped().get_value()))
+                .collect(),
+        }),
+        MetricType::HISTOGRAM => metric::Data::Histogram(Histogram {
+            data_points: metrics
+                .iter()
+                .map(|m| {
+                    let histogram = m.get_histogram();
+                    let buckets: Vec<_> = histogram
+                        .get_bucket()
+                        .iter()
+                        .filter(|b| b.get_upper_bound().is_finite())
+                        .collect();
+                    // Prometheus buckets are cumulative where OTLP counts each bucket, including the overflow bucket
+                    let mut previous = 0;
+                    let mut bucket_counts: Vec<u64> = buckets
+                        .iter()
+                        .map(|b| {
+                            let count = b.get_cumulative_count() - previous;
+                            previous = b.get_cumulative_count();
+                            count
+                        })
+                        .collect();
+                    bucket_counts.push(histogram.get_sample_count() - previous);
+                    HistogramDataPoint {
+                        attributes: attributes(m),
+                        start_time_unix_nano: start,
+                        time_unix_nano: now,
+                        count: histogram.get_sample_count(),
+                        sum: Some(histogram.get_sample_sum()),
+                        bucket_counts,
+                        explicit_bounds: buckets.iter().map(|b| b.get_upper_bound()).collect(),
+                        ..Default::default()
+                    }
+                })
+                .collect(),
+            aggregation_temporality: AggregationTemporality::Cumulative as i32,
+        }),
+        MetricType::SUMMARY => metric::Data::Summary(Summary {
+            data_points: metrics
+                .iter()
+                .map(|m| {
+                    let summary = m.get_summary();
+                    SummaryDataPoint {
+                        attributes: attributes(m),
+                        start_time_unix_nano: start,
+                        time_unix_nano: now,
+                        count: summary.get_sample_count(),
+                        sum: summary.get_sample_sum(),
+                        quantile_values: summary
+                            .get_quantile()
+                            .iter()
+                            .map(|q| summary_data_point::ValueAtQuantile {
+                                quantile: q.get_quantile(),
+                                value: q.get_value(),
+                            })
+                            .collect(),
+                        ..Default::default()
+                    }
+                })
+                .collect(),
+        }),
+    };
+
+    Metric {
+        name: family.get_name().to_string(),
+        description: family.get_help().to_string(),
+        unit: String::new(),
+        data: Some(data),
+    }
+}
+
+/// The resource the signal of an exporter is reported for
+pub(crate) fn resource(config: &OtlpConfig) -> Resource {
+    Resource {
+        attributes: config
+            .resource
+            .iter()
+            .map(|(key, value)| key_value(key, value))
+            .collect(),
+        dropped_attributes_count: 0,
+    }
+}
+
+/// The instrumentation scope of exported signals
+pub(crate) fn scope() -> InstrumentationScope {
+    InstrumentationScope {
+        name: String::from(env!("CARGO_PKG_NAME")),
+        version: String::from(env!("CARGO_PKG_VERSION")),
+        ..Default::default()
+    }
+}
+
+/// Build the export request for the gathered metric families
+fn request(
+    config: &OtlpConfig,
+    families: &[MetricFamily],
+    start: u64,
+) -> ExportMetricsServiceRequest {
+    let now = nanos(SystemTime::now());
+    ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Some(resource(config)),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(scope()),
+                metrics: families
+                    .iter()
+                    .map(|family| convert(family, start, now))
+                    .collect(),
+                schema_url: String::new(),
+            }],
+            schema_url: String::new(),
+        }],
+    }
+}
+
+/// Sends export requests over the configured protocol
+pub(crate) enum Transport {
+    Grpc(Channel),
+    Http(Client<warp::hyper::client::HttpConnector>),
+}
+
+impl Transport {
+    /// Create the [Transport] of an exporter, connecting lazily so it must be created on a runtime
+    pub(crate) fn new(config: &OtlpConfig) -> Result<Transport, String> {
+        match config.protocol {
+            Protocol::Grpc => {
+                let channel = Endpoint::from_shared(config.endpoint.clone())
+                    .map_err(|e| e.to_string())?
+                    .timeout(config.timeout)
+                    .connect_lazy();
+                Ok(Transport::Grpc(channel))
+            }
+            Protocol::HttpProtobuf => Ok(Transport::Http(Client::new())),
+        }
+    }
+
+    /// A gRPC request carrying the configured headers as metadata
+    pub(crate) fn grpc_request<T>(
+        config: &OtlpConfig,
+        message: T,
+    ) -> Result<tonic::Request<T>, String> {
+        let mut request = tonic::Request::new(message);
+        for (name, value) in config.headers.iter() {
+            let name = MetadataKey::from_bytes(name.as_bytes()).map_err(|e| e.to_string())?;
+            let value: AsciiMetadataValue = value
+                .parse()
+                .map_err(|_| format!("invalid header {}", name))?;
+            request.metadata_mut().insert(name, value);
+        }
+        Ok(request)
+    }
+
+    /// Post an encoded request to the endpoint over HTTP
+    pub(crate) async fn post(
+        client: &Client<warp::hyper::client::HttpConnector>,
+        config: &OtlpConfig,
+        body: Vec<u8>,
+    ) -> Result<(), String> {
+        let mut builder = Request::builder()
+            .method(Method::POST)
+            .uri(&config.endpoint)
+            .header("content-type", "application/x-protobuf");
+        for (name, value) in config.headers.iter() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let request = builder.body(Body::from(body)).map_err(|e| e.to_string())?;
+        let resp = tokio::time::timeout(config.timeout, client.request(request))
+            .await
+            .map_err(|_| String::from("timed out"))?
+            .map_err(|e| e.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("collector responded {}", resp.status()));
+        }
+        Ok(())
+    }
+
+    async fn export(
+        &self,
+        config: &OtlpConfig,
+        request: ExportMetricsServiceRequest,
+    ) -> Result<(), String> {
+        match self {
+            Transport::Grpc(channel) => {
+                MetricsServiceClient::new(channel.clone())
+                    .export(Transport::grpc_request(config, request)?)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok(())
+            }
+            Transport::Http(client) => {
+                Transport::post(client, config, request.encode_to_vec()).await
+            }
+        }
+    }
+}
+
+async fn export(transport: &Transport, config: &OtlpConfig, metrics: &Metrics, start: u64) {
+    let mut families = metrics.registry().gather();
+    families.extend(prometheus::gather());
+    match transport
+        .export(config, request(config, &families, start))
+        .await
+    {
+        Ok(()) => debug!("Exported {} metrics over OTLP", families.len()),
+        Err(e) => warn!("OTLP export to {} failed: {}", config.endpoint, e),
+    }
+}
+
+/// Start exporting metrics over OTLP as a [HandleChannel] to be managed by the [UService](crate::UService)
+///
+/// Metrics are exported every interval and a final time when the shutdown signal is received.
+/// Cumulative values are reported since the start time of the [ServiceInfo], so a restarted exporter is not taken for a reset.
+pub async fn otlp_exporter(
+    config: &OtlpConfig,
+    metrics: &Metrics,
+    info: &ServiceInfo,
+) -> HandleChannel {
+    info!("Starting OTLP metrics exporter to {}", config.endpoint);
+
+    let config = config.clone();
+    let start = nanos(info.start_time);
+    let metrics = metrics.clone();
+    let (channel, mut rx) = mpsc::channel(1);
+
+    let handle = spawn_component("otlp_exporter", async move {
+        let transport = match Transport::new(&config) {
+            Ok(transport) => transport,
+            Err(e) => {
+                warn!("Invalid OTLP endpoint {}: {}", config.endpoint, e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = sleep(config.interval) => {},
+                _ = rx.recv() => break,
+            }
+            export(&transport, &config, &metrics, start).await;
+        }
+
+        export(&transport, &config, &metrics, start).await;
+        info!("OTLP metrics exporter closed");
+    });
+
+    HandleChannel { handle, channel }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{HistogramOpts, HistogramVec, Registry};
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    #[test]
+    fn otlp_histogram_conversion() {
+        //! Test that cumulative Prometheus buckets become per bucket OTLP counts
+        let registry = Registry::new();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("latency_seconds", "Latency").buckets(vec![0.1, 1.0]),
+            &["route"],
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        for value in [0.05, 0.5, 0.7, 5.0] {
+            histogram.with_label_values(&["/a"]).observe(value);
+        }
+
+        let metric = convert(&registry.gather()[0], 1, 2);
+        let point = match metric.data {
+            Some(metric::Data::Histogram(histogram)) => histogram.data_points[0].clone(),
+            _ => panic!("histogram converted"),
+        };
+        assert_eq!(point.explicit_bounds, vec![0.1, 1.0]);
+        assert_eq!(point.bucket_counts, vec![1, 2, 1]);
+        assert_eq!(point.count, 4);
+        assert_eq!(point.attributes, vec![key_value("route", "/a")]);
+    }
+
+    #[tokio::test]
+    async fn otlp_http_final_export() {
+        //! Test that metrics and resource attributes are exported over HTTP when shut down
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        let collector = warp::post()
+            .and(warp::path!("v1" / "metrics"))
+            .and(warp::header::<String>("x-tenant"))
+            .and(warp::body::bytes())
+            .map(move |_tenant: String, body: warp::hyper::body::Bytes| {
+                let request = ExportMetricsServiceRequest::decode(body).unwrap();
+                received.lock().unwrap().push(request);
+                ""
+            });
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let (_addr, server) =
+            warp::serve(collector).bind_with_graceful_shutdown(([127, 0, 0, 1], 7985), async {
+                stopped.await.ok();
+            });
+        let server = tokio::spawn(server);
+
+        let mut service = UServiceConfig::new("exported");
+        service.metrics.pod = Some(String::from("exported-1234"));
+        let metrics = Metrics::with_config(&service.name, &service.env, &service.metrics);
+        metrics.counter("jobs", "Jobs run").unwrap().inc();
+        let mut config = OtlpConfig::new(
+            "http://127.0.0.1:7985/v1/metrics",
+            Protocol::HttpProtobuf,
+            &service,
+        );
+        config.interval = Duration::from_secs(3600);
+        config.headers = vec![(String::from("x-tenant"), String::from("test"))];
+
+        let info = ServiceInfo::new(&service);
+        let exporter = otlp_exporter(&config, &metrics, &info).await;
+        exporter.channel.send(()).await.unwrap();
+        exporter.handle.await.unwrap();
+
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 1);
+            let resource_metrics = &requests[0].resource_metrics[0];
+            let resource = resource_metrics.resource.as_ref().unwrap();
+            assert!(resource
+                .attributes
+                .contains(&key_value("service.name", "exported")));
+            assert!(resource
+                .attributes
+                .contains(&key_value("k8s.pod.name", "exported-1234")));
+            let jobs = resource_metrics.scope_metrics[0]
+                .metrics
+                .iter()
+                .find(|m| m.name == "jobs")
+                .expect("counter exported");
+            match &jobs.data {
+                Some(metric::Data::Sum(sum)) => {
+                    assert!(sum.is_monotonic);
+                    assert_eq!(
+                        sum.data_points[0].value,
+                        Some(number_data_point::Value::AsDouble(1.0))
+                    );
+                    assert_eq!(
+                        sum.data_points[0].start_time_unix_nano,
+                        nanos(info.start_time)
+                    );
+                }
+                _ => panic!("counter exported as a sum"),
+            }
+        }
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
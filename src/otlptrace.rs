@@ -0,0 +1,393 @@
+//! Distributed tracing with W3C trace context, exporting spans over OTLP
+//!
+//! The [layer] records tracing spans as OpenTelemetry spans. Requests served by [serve](crate::logging::serve) continue the trace
+//! of their `traceparent` and `tracestate` headers, or start a new one, in a server span, and [send] makes a client request
+//! in a client span injecting its context. Spans declaring an `otel.kind` field start a recorded span tree and spans nested
+//! in them are recorded as children, other spans such as those of components are not recorded.
+//! Spans and events at info level and above are recorded whatever the log filter, so changing the log level does not stop tracing.
+//!
+//! Finished spans of sampled traces are queued and exported in batches by [trace_exporter] to the collector set by
+//! [OtlpConfig::signal_from_env] for [Signal::Traces](crate::otlp::Signal::Traces), with a final export when the
+//! [UService](crate::UService) shuts down. The trace id of a request is written in its logs, access log and exemplars.
+//!
+//! Only available with the `trace` feature.
+
+use crate::otlp::{self, OtlpConfig, Transport};
+use crate::{spawn_component, HandleChannel};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+use opentelemetry::{global, Context};
+use opentelemetry_proto::tonic::collector::trace::v1::trace_service_client::TraceServiceClient;
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::trace::v1::{ResourceSpans, ScopeSpans};
+use opentelemetry_sdk::export::trace::SpanData;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Sampler, SpanProcessor, TracerProvider};
+use prost::Message;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, field, info, info_span, warn, Instrument, Level, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::dynamic_filter_fn;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+use warp::http::{HeaderMap, HeaderName, HeaderValue, Request, Response};
+use warp::hyper::client::connect::Connect;
+use warp::hyper::{Body, Client};
+
+/// Finished spans held for export, further spans are dropped
+const QUEUE_SIZE: usize = 2048;
+
+/// Maximum spans sent in one export request
+const BATCH_SIZE: usize = 512;
+
+/// Spans finished and not yet taken by the [trace_exporter]
+static QUEUE: Mutex<Option<mpsc::Receiver<SpanData>>> = Mutex::new(None);
+
+/// Spans dropped as the queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Queues finished spans of sampled traces for the [trace_exporter]
+#[derive(Debug)]
+struct QueueProcessor {
+    sender: mpsc::Sender<SpanData>,
+}
+
+impl SpanProcessor for QueueProcessor {
+    fn on_start(&self, _span: &mut trace::Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        // Logging here would re-enter the subscriber closing the span, so drops are reported by the exporter
+        if span.span_context.is_sampled() && self.sender.try_send(span).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn force_flush(&self) -> opentelemetry::trace::TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> opentelemetry::trace::TraceResult<()> {
+        Ok(())
+    }
+}
+
+/// Layer recording spans as OpenTelemetry spans, sampling the given ratio of new traces
+///
+/// Also installs the W3C trace context propagator and the tracer provider globally.
+pub fn layer<S>(ratio: f64) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    *QUEUE.lock().unwrap() = Some(receiver);
+
+    let provider = TracerProvider::builder()
+        .with_span_processor(QueueProcessor { sender })
+        .with_config(trace::config().with_sampler(Sampler::ParentBased(Box::new(
+            Sampler::TraceIdRatioBased(ratio),
+        ))))
+        .build();
+    let tracer = provider.versioned_tracer(
+        env!("CARGO_PKG_NAME"),
+        Some(env!("CARGO_PKG_VERSION")),
+        None::<&str>,
+        None,
+    );
+    global::set_tracer_provider(provider);
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(
+            dynamic_filter_fn(|metadata, cx| {
+                metadata.level() <= &Level::INFO
+                    && (!metadata.is_span()
+                        || metadata.fields().field("otel.kind").is_some()
+                        || cx.lookup_current().is_some())
+            })
+            .with_max_level_hint(LevelFilter::INFO),
+        )
+}
+
+/// Reads trace context from http headers
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/// Writes trace context to http headers
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Make a span with an `otel.kind` field the server span of a request, continuing the trace of its headers if any
+pub fn server_span(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+    span.record("otel.kind", "server");
+}
+
+/// Trace id of a span if its trace is sampled
+pub fn trace_id(span: &Span) -> Option<String> {
+    let context = span.context();
+    let span_context = context.span().span_context().clone();
+    (span_context.is_valid() && span_context.is_sampled())
+        .then(|| span_context.trace_id().to_string())
+}
+
+/// Add the trace context of the current span to the headers of an outgoing request
+pub fn inject(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// Send a request in a client span, injecting its trace context
+pub async fn send<C>(
+    client: &Client<C, Body>,
+    mut request: Request<Body>,
+) -> warp::hyper::Result<Response<Body>>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let span = info_span!(
+        "client",
+        otel.kind = "client",
+        method = %request.method(),
+        url = %request.uri(),
+        status = field::Empty,
+    );
+    {
+        let _entered = span.enter();
+        inject(request.headers_mut());
+    }
+    let response = client.request(request).instrument(span.clone()).await;
+    if let Ok(response) = &response {
+        span.record("status", response.status().as_u16());
+    }
+    response
+}
+
+/// Build the export request for finished spans
+fn request(config: &OtlpConfig, spans: Vec<SpanData>) -> ExportTraceServiceRequest {
+    ExportTraceServiceRequest {
+        resource_spans: vec![ResourceSpans {
+            resource: Some(otlp::resource(config)),
+            scope_spans: vec![ScopeSpans {
+                scope: Some(otlp::scope()),
+                spans: spans
+                    .into_iter()
+                    .flat_map(|span| ResourceSpans::from(span).scope_spans)
+                    .flat_map(|scope| scope.spans)
+                    .collect(),
+                schema_url: String::new(),
+            }],
+            schema_url: String::new(),
+        }],
+    }
+}
+
+async fn send_request(
+    transport: &Transport,
+    config: &OtlpConfig,
+    request: ExportTraceServiceRequest,
+) -> Result<(), String> {
+    match transport {
+        Transport::Grpc(channel) => {
+            TraceServiceClient::new(channel.clone())
+                .export(Transport::grpc_request(config, request)?)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(())
+        }
+        Transport::Http(client) => Transport::post(client, config, request.encode_to_vec()).await,
+    }
+}
+
+/// Export the queued spans in batches
+async fn export(transport: &Transport, config: &OtlpConfig, queue: &mut mpsc::Receiver<SpanData>) {
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        warn!("Dropped {} spans as the export queue was full", dropped);
+    }
+    loop {
+        let mut spans = Vec::new();
+        while spans.len() < BATCH_SIZE {
+            match queue.try_recv() {
+                Ok(span) => spans.push(span),
+                Err(_) => break,
+            }
+        }
+        if spans.is_empty() {
+            return;
+        }
+        let count = spans.len();
+        match send_request(transport, config, request(config, spans)).await {
+            Ok(()) => debug!("Exported {} spans over OTLP", count),
+            Err(e) => warn!("OTLP span export to {} failed: {}", config.endpoint, e),
+        }
+        if count < BATCH_SIZE {
+            return;
+        }
+    }
+}
+
+/// Start exporting the spans recorded by the [layer] over OTLP as a [HandleChannel] to be managed by the [UService](crate::UService)
+///
+/// Spans are exported every interval and a final time when the shutdown signal is received.
+pub async fn trace_exporter(config: &OtlpConfig) -> HandleChannel {
+    info!("Starting OTLP trace exporter to {}", config.endpoint);
+
+    let config = config.clone();
+    let (channel, mut rx) = mpsc::channel(1);
+
+    let handle = spawn_component("trace_exporter", async move {
+        let queue = QUEUE.lock().unwrap().take();
+        let mut queue = match queue {
+            Some(queue) => queue,
+            None => {
+                warn!("No spans recorded as the tracing layer is not installed");
+                return;
+            }
+        };
+        let transport = match Transport::new(&config) {
+            Ok(transport) => transport,
+            Err(e) => {
+                warn!("Invalid OTLP endpoint {}: {}", config.endpoint, e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                _ = sleep(config.interval) => {},
+                _ = rx.recv() => break,
+            }
+            export(&transport, &config, &mut queue).await;
+        }
+
+        export(&transport, &config, &mut queue).await;
+        info!("OTLP trace exporter closed");
+    });
+
+    HandleChannel { handle, channel }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::{log_layer, LogConfig, LogLevel};
+    use crate::otlp::Protocol;
+    use crate::UServiceConfig;
+    use opentelemetry_proto::tonic::trace::v1::span::SpanKind;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::EnvFilter;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn trace_continued_and_exported() {
+        //! Test that a server span continues the trace of its request, is injected into client requests and exported,
+        //! with logging at warn
+        let (filter, _level) = LogLevel::new(EnvFilter::new("warn"));
+        let (logs, _) = log_layer(&LogConfig::new("traced"), filter);
+        let _default = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(logs).with(layer(1.0)),
+        );
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        let collector = warp::post()
+            .and(warp::path!("v1" / "traces"))
+            .and(warp::body::bytes())
+            .map(move |body: warp::hyper::body::Bytes| {
+                let request = ExportTraceServiceRequest::decode(body).unwrap();
+                received.lock().unwrap().push(request);
+                ""
+            });
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let (_addr, server) =
+            warp::serve(collector).bind_with_graceful_shutdown(([127, 0, 0, 1], 7987), async {
+                stopped.await.ok();
+            });
+        let server = tokio::spawn(server);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        let span = info_span!("request", otel.kind = field::Empty);
+        server_span(&span, &headers);
+        assert_eq!(
+            trace_id(&span).as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+        let mut outgoing = HeaderMap::new();
+        span.in_scope(|| info_span!("handler").in_scope(|| inject(&mut outgoing)));
+        let traceparent = outgoing["traceparent"].to_str().unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+        drop(span);
+        info_span!("component").in_scope(|| ());
+
+        let service = UServiceConfig::new("traced");
+        let mut config = OtlpConfig::new(
+            "http://127.0.0.1:7987/v1/traces",
+            Protocol::HttpProtobuf,
+            &service,
+        );
+        config.interval = Duration::from_secs(3600);
+        let exporter = trace_exporter(&config).await;
+        exporter.channel.send(()).await.unwrap();
+        exporter.handle.await.unwrap();
+
+        {
+            let requests = requests.lock().unwrap();
+            assert_eq!(requests.len(), 1);
+            let resource_spans = &requests[0].resource_spans[0];
+            assert!(resource_spans
+                .resource
+                .as_ref()
+                .unwrap()
+                .attributes
+                .contains(&otlp::key_value("service.name", "traced")));
+            let spans = &resource_spans.scope_spans[0].spans;
+            let names: Vec<_> = spans.iter().map(|span| span.name.as_str()).collect();
+            assert_eq!(names, vec!["handler", "request"]);
+            let request = &spans[1];
+            assert_eq!(request.kind, SpanKind::Server as i32);
+            assert_eq!(hex(&request.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+            assert_eq!(hex(&request.parent_span_id), "00f067aa0ba902b7");
+            assert_eq!(spans[0].parent_span_id, request.span_id);
+        }
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
@@ -0,0 +1,143 @@
+//! High resolution latency histograms for exact percentiles
+//!
+//! Prometheus histograms only report which bucket a percentile falls in. [Percentiles] records every latency in an
+//! [HDR histogram](https://hdrhistogram.github.io/HdrHistogram/) so percentiles can be queried in process to 3 significant figures.
+//!
+//! [Percentiles] is also a collector exporting the [QUANTILES] of each key as a summary.
+//!
+//! Only available with the `hdr` feature.
+
+use hdrhistogram::Histogram;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType, Quantile, Summary};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Highest latency tracked, longer latencies are recorded as this value
+const MAX_MICROS: u64 = 60 * 60 * 1_000_000;
+
+/// Quantiles exported for each key
+pub const QUANTILES: &[f64] = &[0.5, 0.9, 0.99];
+
+/// Store of high resolution latency histograms by key
+///
+/// Cloning shares the store.
+#[derive(Clone)]
+pub struct Percentiles {
+    histograms: Arc<Mutex<HashMap<String, Histogram<u64>>>>,
+    desc: Desc,
+}
+
+impl Percentiles {
+    /// Create a store exported as a summary with the given name, help and label holding the key
+    pub fn new(name: &str, help: &str, label: &str) -> Percentiles {
+        Percentiles {
+            histograms: Arc::new(Mutex::new(HashMap::new())),
+            desc: Desc::new(
+                name.to_string(),
+                help.to_string(),
+                vec![label.to_string()],
+                HashMap::new(),
+            )
+            .expect("valid metric description"),
+        }
+    }
+
+    /// Record a latency under a key
+    pub fn record(&self, key: &str, latency: Duration) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(key.to_string()).or_insert_with(|| {
+            Histogram::new_with_bounds(1, MAX_MICROS, 3).expect("valid histogram bounds")
+        });
+        histogram.saturating_record(latency.as_micros().clamp(1, MAX_MICROS as u128) as u64);
+    }
+
+    /// The latency at a quantile between 0 and 1 of those recorded under a key
+    pub fn quantile(&self, key: &str, quantile: f64) -> Option<Duration> {
+        let histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.get(key).filter(|h| !h.is_empty())?;
+        Some(Duration::from_micros(histogram.value_at_quantile(quantile)))
+    }
+
+    /// Number of latencies recorded under a key
+    pub fn count(&self, key: &str) -> u64 {
+        let histograms = self.histograms.lock().unwrap();
+        histograms.get(key).map_or(0, |h| h.len())
+    }
+
+    /// Clear the latencies recorded under every key
+    pub fn reset(&self) {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms.values_mut().for_each(Histogram::reset);
+    }
+}
+
+impl Collector for Percentiles {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let histograms = self.histograms.lock().unwrap();
+        if histograms.is_empty() {
+            return Vec::new();
+        }
+        let mut keys: Vec<_> = histograms.keys().collect();
+        keys.sort();
+        let metrics = keys
+            .into_iter()
+            .map(|key| {
+                let histogram = &histograms[key];
+                let mut summary = Summary::default();
+                summary.set_sample_count(histogram.len());
+                // Recorded values are microseconds to 3 significant figures, so the sum is as precise
+                summary.set_sample_sum(histogram.mean() * histogram.len() as f64 / 1e6);
+                for quantile in QUANTILES {
+                    let mut value = Quantile::default();
+                    value.set_quantile(*quantile);
+                    value.set_value(histogram.value_at_quantile(*quantile) as f64 / 1e6);
+                    summary.mut_quantile().push(value);
+                }
+                let mut label = LabelPair::default();
+                label.set_name(self.desc.variable_labels[0].clone());
+                label.set_value(key.clone());
+                let mut metric = Metric::default();
+                metric.set_label(vec![label].into());
+                metric.set_summary(summary);
+                metric
+            })
+            .collect::<Vec<_>>();
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::SUMMARY);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_exact() {
+        //! Test that percentiles are reported to within the configured precision
+        let percentiles = Percentiles::new("latency_seconds", "Latency", "key");
+        for ms in 1..=200 {
+            percentiles.record("/sample", Duration::from_millis(ms));
+        }
+
+        assert_eq!(percentiles.count("/sample"), 200);
+        let p50 = percentiles.quantile("/sample", 0.5).unwrap();
+        assert!(p50 >= Duration::from_micros(99_900) && p50 <= Duration::from_micros(100_100));
+        let p99 = percentiles.quantile("/sample", 0.99).unwrap();
+        assert!(p99 >= Duration::from_micros(197_800) && p99 <= Duration::from_micros(198_200));
+        assert_eq!(percentiles.quantile("/other", 0.5), None);
+
+        percentiles.reset();
+        assert_eq!(percentiles.quantile("/sample", 0.5), None);
+    }
+}
@@ -0,0 +1,263 @@
+//! Push metrics to a [Prometheus Pushgateway](https://github.com/prometheus/pushgateway)
+//!
+//! Short lived services can exit before Prometheus scrapes them, so [pushgateway] pushes the metrics of the service on an interval
+//! and a final time when the [UService](crate::UService) shuts down.
+//! Each push replaces the metrics in the group identified by the job and grouping key and failed pushes are retried with exponential backoff.
+
+use crate::env_parse;
+use crate::metrics::Metrics;
+use crate::{spawn_component, HandleChannel};
+use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
+use std::env;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+use warp::http::{Method, Request, StatusCode};
+use warp::hyper::{Body, Client};
+
+/// Configuration of the Pushgateway pusher
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PushgatewayConfig {
+    /// Base URL of the Pushgateway eg `http://pushgateway:9091`
+    pub url: String,
+    /// Job the metrics are grouped under
+    pub job: String,
+    /// Additional labels identifying the group
+    pub grouping: Vec<(String, String)>,
+    /// Time between pushes
+    pub interval: Duration,
+    /// Number of times a failed push is retried
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each following retry
+    pub backoff: Duration,
+}
+
+impl PushgatewayConfig {
+    /// Create a [PushgatewayConfig] for a URL with the job named after the service
+    pub fn new(url: &str, job: &str) -> PushgatewayConfig {
+        PushgatewayConfig {
+            url: url.trim_end_matches('/').to_string(),
+            job: job.to_string(),
+            grouping: Vec::new(),
+            interval: Duration::from_secs(15),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+
+    /// Create a [PushgatewayConfig] from environment variables, if `USERVICE_PUSHGATEWAY_URL` is set
+    ///
+    ///  * `USERVICE_PUSHGATEWAY_URL` for the base URL of the Pushgateway
+    ///  * `USERVICE_PUSHGATEWAY_JOB` for the job, defaulting to the name of the service
+    ///  * `USERVICE_PUSHGATEWAY_GROUPING` for the grouping key as `name=value` pairs separated by commas
+    ///  * `USERVICE_PUSHGATEWAY_INTERVAL` for the seconds between pushes, greater than 0
+    ///  * `USERVICE_PUSHGATEWAY_RETRIES` for the number of retries of a failed push
+    pub fn from_env(name: &str) -> Option<PushgatewayConfig> {
+        let url = env::var("USERVICE_PUSHGATEWAY_URL").ok()?;
+        let job = env::var("USERVICE_PUSHGATEWAY_JOB").unwrap_or_else(|_| name.to_string());
+        let defaults = PushgatewayConfig::new(&url, &job);
+        Some(PushgatewayConfig {
+            grouping: env_parse("USERVICE_PUSHGATEWAY_GROUPING", parse_grouping)
+                .unwrap_or(defaults.grouping),
+            interval: env_parse("USERVICE_PUSHGATEWAY_INTERVAL", |v| {
+                v.parse().ok().filter(|secs| *secs > 0)
+            })
+            .map(Duration::from_secs)
+            .unwrap_or(defaults.interval),
+            retries: env_parse("USERVICE_PUSHGATEWAY_RETRIES", |v| v.parse().ok())
+                .unwrap_or(defaults.retries),
+            ..defaults
+        })
+    }
+
+    /// URL of the group the metrics are pushed to
+    pub fn group_url(&self) -> String {
+        let mut url = format!("{}/metrics/job/{}", self.url, encode_segment(&self.job));
+        for (name, value) in self.grouping.iter() {
+            if value.is_empty() {
+                // An empty value can only be given base64 encoded
+                url.push_str(&format!("/{}@base64/=", name));
+            } else {
+                url.push_str(&format!("/{}/{}", name, encode_segment(value)));
+            }
+        }
+        url
+    }
+}
+
+/// Parse a grouping key from `name=value` pairs separated by commas
+fn parse_grouping(value: &str) -> Option<Vec<(String, String)>> {
+    value
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Percent encode a path segment
+fn encode_segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Encode the metrics of the service and the process in the Prometheus text format
+fn encode(metrics: &Metrics) -> Vec<u8> {
+    let mut families = metrics.registry().gather();
+    families.extend(prometheus::gather());
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&families, &mut buffer) {
+        warn!("Could not encode metrics for the Pushgateway: {}", e);
+    }
+    buffer
+}
+
+/// Push the metrics once, retrying with backoff on failure
+async fn push(config: &PushgatewayConfig, metrics: &Metrics) -> Result<(), String> {
+    let client = Client::new();
+    let url = config.group_url();
+    let mut backoff = config.backoff;
+
+    for attempt in 0..=config.retries {
+        if attempt > 0 {
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(&url)
+            .header("content-type", TextEncoder::new().format_type())
+            .body(Body::from(encode(metrics)))
+            .map_err(|e| e.to_string())?;
+        match client.request(request).await {
+            Ok(resp) if resp.status().is_success() => {
+                debug!("Pushed metrics to {}", url);
+                return Ok(());
+            }
+            Ok(resp) if resp.status() == StatusCode::BAD_REQUEST => {
+                return Err(format!("Pushgateway rejected metrics: {}", resp.status()));
+            }
+            Ok(resp) => warn!("Push attempt {} failed: {}", attempt + 1, resp.status()),
+            Err(e) => warn!("Push attempt {} failed: {}", attempt + 1, e),
+        }
+    }
+    Err(format!(
+        "Push to {} failed after {} attempts",
+        url,
+        config.retries + 1
+    ))
+}
+
+/// Start pushing metrics to the Pushgateway as a [HandleChannel] to be managed by the [UService](crate::UService)
+///
+/// Metrics are pushed every interval and a final time when the shutdown signal is received.
+pub async fn pushgateway(config: &PushgatewayConfig, metrics: &Metrics) -> HandleChannel {
+    info!("Starting Pushgateway pusher to {}", config.group_url());
+
+    let config = config.clone();
+    let metrics = metrics.clone();
+    let (channel, mut rx) = mpsc::channel(1);
+
+    let handle = spawn_component("pushgateway", async move {
+        loop {
+            tokio::select! {
+                _ = sleep(config.interval) => {},
+                _ = rx.recv() => break,
+            }
+            if let Err(e) = push(&config, &metrics).await {
+                warn!("{}", e);
+            }
+        }
+
+        if let Err(e) = push(&config, &metrics).await {
+            warn!("Final {}", e);
+        }
+        info!("Pushgateway pusher closed");
+    });
+
+    HandleChannel { handle, channel }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    #[test]
+    fn grouping_url() {
+        //! Test that the job and grouping key are encoded in the group URL
+        let mut config = PushgatewayConfig::new("http://pushgateway:9091/", "batch job");
+        config.grouping = parse_grouping("instance=a/b, shard=").unwrap();
+        assert_eq!(
+            config.group_url(),
+            "http://pushgateway:9091/metrics/job/batch%20job/instance/a%2Fb/shard@base64/="
+        );
+        assert_eq!(parse_grouping("instance"), None);
+    }
+
+    #[tokio::test]
+    async fn push_retry_and_final() {
+        //! Test that failed pushes are retried and the final push is sent on shutdown
+        let pushes = Arc::new(Mutex::new(Vec::new()));
+        let received = pushes.clone();
+        let stub = warp::put()
+            .and(warp::path::full())
+            .and(warp::body::bytes())
+            .map(
+                move |path: warp::path::FullPath, body: warp::hyper::body::Bytes| {
+                    let mut pushes = received.lock().unwrap();
+                    pushes.push((path.as_str().to_string(), body.to_vec()));
+                    // Fail the first push to force a retry
+                    let status = if pushes.len() == 1 {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    } else {
+                        StatusCode::OK
+                    };
+                    warp::reply::with_status("", status)
+                },
+            );
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let (_addr, server) =
+            warp::serve(stub).bind_with_graceful_shutdown(([127, 0, 0, 1], 7984), async {
+                stopped.await.ok();
+            });
+        let server = tokio::spawn(server);
+
+        let metrics = Metrics::new("pushed", "test");
+        metrics.counter("jobs", "Jobs run").unwrap().inc();
+        let mut config = PushgatewayConfig::new("http://127.0.0.1:7984", "batch");
+        config.grouping = vec![(String::from("shard"), String::from("1"))];
+        config.interval = Duration::from_secs(3600);
+        config.backoff = Duration::from_millis(10);
+
+        let pusher = pushgateway(&config, &metrics).await;
+        pusher.channel.send(()).await.unwrap();
+        pusher.handle.await.unwrap();
+
+        {
+            let pushes = pushes.lock().unwrap();
+            assert_eq!(pushes.len(), 2);
+            assert_eq!(pushes[1].0, "/metrics/job/batch/shard/1");
+            let body = String::from_utf8(pushes[1].1.clone()).unwrap();
+            assert!(body
+                .lines()
+                .any(|line| line.starts_with("jobs{") && line.contains("service=\"pushed\"")));
+        }
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
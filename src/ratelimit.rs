@@ -0,0 +1,428 @@
+//! Rate limiting of repeated log events
+//!
+//! A failing dependency can make a service log the same error thousands of times a second.
+//! The [RateLimit] filter lets through a burst of events per key in each window and suppresses the rest from the layers it filters,
+//! keying events by their callsite or by their callsite and message as set by [RateLimitKey].
+//!
+//! Suppressed events are counted in `log_events_suppressed_total` and summarised by [log_rate_limit]
+//! once their window has passed as a `message repeated N times` event at the level of the suppressed events.
+//!
+//! At most [MAX_KEYS] messages are tracked in a window. Once full, events with a new message share the limit of their callsite,
+//! so a flood of distinct messages is still limited. Access log lines, under the `access` target, are never limited
+//! as each is a distinct request rather than a repeat.
+
+use crate::metrics::Metrics;
+use crate::{spawn_component, HandleChannel};
+use prometheus::{IntCounterVec, Opts};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::callsite::Identifier;
+use tracing::field::{Field, Visit};
+use tracing::subscriber::Interest;
+use tracing::{debug, error, info, trace, warn, Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Filter};
+
+/// Maximum number of messages tracked in a window, events with further messages are limited by their callsite
+pub const MAX_KEYS: usize = 10_000;
+
+/// Length in bytes the message of a key is kept to for its summary
+const MAX_MESSAGE: usize = 256;
+
+/// Targets whose events are never limited, summaries and access log lines
+const UNLIMITED_TARGETS: &[&str] = &[module_path!(), "access"];
+
+/// What makes events repeats of each other
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// Events from the same callsite
+    ///
+    /// Records of the `log` crate share one callsite per level.
+    Callsite,
+    /// Events from the same callsite with the same message
+    Message,
+}
+
+impl RateLimitKey {
+    /// Parse `callsite` or `message`
+    pub fn parse(key: &str) -> Option<RateLimitKey> {
+        match key.trim().to_ascii_lowercase().as_str() {
+            "callsite" => Some(RateLimitKey::Callsite),
+            "message" => Some(RateLimitKey::Message),
+            _ => None,
+        }
+    }
+}
+
+/// Configuration of log rate limiting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitConfig {
+    /// Events of a key let through in each window
+    pub burst: u32,
+    /// Length of a window
+    pub window: Duration,
+    /// What makes events repeats of each other
+    pub key: RateLimitKey,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> RateLimitConfig {
+        RateLimitConfig {
+            burst: 20,
+            window: Duration::from_secs(10),
+            key: RateLimitKey::Message,
+        }
+    }
+}
+
+/// Events seen of a key
+struct Entry {
+    window_start: Instant,
+    count: u32,
+    /// Suppressed since the last summary
+    suppressed: u64,
+    level: Level,
+    target: String,
+    message: String,
+}
+
+/// The callsite of events and the hash of their message, none when keyed by callsite only
+type Key = (Identifier, Option<u64>);
+
+struct State {
+    entries: HashMap<Key, Entry>,
+}
+
+/// Per layer filter suppressing events repeated more than the burst of a window
+///
+/// Events are only suppressed for the layers it filters, so other layers such as tracing still see every event.
+/// Summaries of suppressed events are only written by [flush](RateLimit::flush).
+#[derive(Clone)]
+pub struct RateLimit {
+    config: RateLimitConfig,
+    state: Arc<Mutex<State>>,
+    suppressed: IntCounterVec,
+}
+
+impl RateLimit {
+    /// Create a [RateLimit] layer
+    pub fn new(config: &RateLimitConfig) -> RateLimit {
+        RateLimit {
+            config: *config,
+            state: Arc::new(Mutex::new(State {
+                entries: HashMap::new(),
+            })),
+            suppressed: IntCounterVec::new(
+                Opts::new(
+                    "log_events_suppressed_total",
+                    "Log events suppressed by rate limiting",
+                ),
+                &["level"],
+            )
+            .expect("metric can be created"),
+        }
+    }
+
+    /// Counter of suppressed events by level
+    pub fn suppressed(&self) -> &IntCounterVec {
+        &self.suppressed
+    }
+
+    /// Log a summary of each key with suppressed events whose window has passed, forgetting keys with none
+    ///
+    /// Must not be called while handling an event, as the summaries are events themselves.
+    pub fn flush(&self) {
+        let summaries: Vec<Entry> = {
+            let mut state = self.state.lock().unwrap();
+            let window = self.config.window;
+            let mut summaries = Vec::new();
+            state.entries.retain(|_, entry| {
+                if entry.window_start.elapsed() < window {
+                    return true;
+                }
+                if entry.suppressed > 0 {
+                    summaries.push(Entry {
+                        target: entry.target.clone(),
+                        message: entry.message.clone(),
+                        ..*entry
+                    });
+                }
+                false
+            });
+            summaries
+        };
+        for entry in summaries {
+            summarise(&entry);
+        }
+    }
+
+    /// Count an event against its key, true if it is within the burst
+    fn admit(&self, event: &Event<'_>) -> bool {
+        let metadata = event.metadata();
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+        let mut key = match self.config.key {
+            RateLimitKey::Callsite => (metadata.callsite(), None),
+            RateLimitKey::Message => {
+                let mut hasher = DefaultHasher::new();
+                message.0.hash(&mut hasher);
+                (metadata.callsite(), Some(hasher.finish()))
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if !state.entries.contains_key(&key) && state.entries.len() >= MAX_KEYS {
+            // Callsites are few, so limiting by callsite keeps the entries bounded
+            key.1 = None;
+        }
+        let entry = state.entries.entry(key).or_insert_with(|| Entry {
+            window_start: Instant::now(),
+            count: 0,
+            suppressed: 0,
+            level: *metadata.level(),
+            target: metadata.target().to_string(),
+            message: truncate(message.0),
+        });
+        if entry.window_start.elapsed() >= self.config.window {
+            entry.window_start = Instant::now();
+            entry.count = 0;
+        }
+        entry.count = entry.count.saturating_add(1);
+        if entry.count <= self.config.burst {
+            return true;
+        }
+        entry.suppressed += 1;
+        drop(state);
+        self.suppressed
+            .with_label_values(&[metadata.level().as_str()])
+            .inc();
+        false
+    }
+}
+
+/// Shorten a message to at most [MAX_MESSAGE] bytes
+fn truncate(mut message: String) -> String {
+    if message.len() > MAX_MESSAGE {
+        let mut end = MAX_MESSAGE;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+        message.push_str("...");
+    }
+    message
+}
+
+/// Log the summary of the suppressed events of a key at their level
+fn summarise(entry: &Entry) {
+    let (count, source, message) = (entry.suppressed, &entry.target, &entry.message);
+    match entry.level {
+        Level::ERROR => {
+            error!(suppressed = count, source = %source, "message repeated {} times: {}", count, message)
+        }
+        Level::WARN => {
+            warn!(suppressed = count, source = %source, "message repeated {} times: {}", count, message)
+        }
+        Level::INFO => {
+            info!(suppressed = count, source = %source, "message repeated {} times: {}", count, message)
+        }
+        Level::DEBUG => {
+            debug!(suppressed = count, source = %source, "message repeated {} times: {}", count, message)
+        }
+        Level::TRACE => {
+            trace!(suppressed = count, source = %source, "message repeated {} times: {}", count, message)
+        }
+    }
+}
+
+/// Collects the message of an event
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+impl<S: Subscriber> Filter<S> for RateLimit {
+    fn enabled(&self, _metadata: &Metadata<'_>, _ctx: &Context<'_, S>) -> bool {
+        true
+    }
+
+    /// Always asked per event, as [event_enabled](Filter::event_enabled) is only consistent with a per event [enabled](Filter::enabled)
+    fn callsite_enabled(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        Interest::sometimes()
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, _ctx: &Context<'_, S>) -> bool {
+        UNLIMITED_TARGETS.contains(&event.metadata().target()) || self.admit(event)
+    }
+}
+
+/// Start summarising suppressed events as a [HandleChannel] to be managed by the [UService](crate::UService)
+///
+/// The counter of suppressed events is registered with the [Metrics] of the service.
+pub async fn log_rate_limit(rate_limit: &RateLimit, metrics: &Metrics) -> HandleChannel {
+    info!("Starting log rate limit summaries");
+    metrics.register(rate_limit.suppressed());
+
+    let rate_limit = rate_limit.clone();
+    let (channel, mut rx) = mpsc::channel(1);
+
+    let handle = spawn_component("log_rate_limit", async move {
+        loop {
+            tokio::select! {
+                _ = sleep(rate_limit.config.window) => {},
+                _ = rx.recv() => break,
+            }
+            rate_limit.flush();
+        }
+        rate_limit.flush();
+        info!("Log rate limit summaries closed");
+    });
+
+    HandleChannel { handle, channel }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::{Layer, SubscriberExt};
+
+    /// Layer collecting the messages of the events it sees
+    #[derive(Clone, Default)]
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for Messages {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut message = MessageVisitor(String::new());
+            event.record(&mut message);
+            self.0.lock().unwrap().push(message.0);
+        }
+    }
+
+    #[test]
+    fn repeats_suppressed_and_summarised() {
+        //! Test that events beyond the burst are suppressed, counted and summarised after the window,
+        //! only for the filtered layer
+        let config = RateLimitConfig {
+            burst: 2,
+            window: Duration::from_millis(50),
+            key: RateLimitKey::Message,
+        };
+        let rate_limit = RateLimit::new(&config);
+        let messages = Messages::default();
+        let unfiltered = Messages::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(messages.clone().with_filter(rate_limit.clone()))
+            .with(unfiltered.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..5 {
+                error!("Connection refused");
+                error!("Timed out");
+            }
+            std::thread::sleep(config.window);
+            rate_limit.flush();
+            error!("Connection refused");
+        });
+
+        let messages = messages.0.lock().unwrap();
+        assert_eq!(
+            messages
+                .iter()
+                .filter(|m| *m == "Connection refused")
+                .count(),
+            3
+        );
+        assert_eq!(messages.iter().filter(|m| *m == "Timed out").count(), 2);
+        assert!(messages.contains(&String::from("message repeated 3 times: Timed out")));
+        let unfiltered = unfiltered.0.lock().unwrap();
+        assert_eq!(unfiltered.iter().filter(|m| *m == "Timed out").count(), 5);
+        assert_eq!(
+            rate_limit.suppressed().with_label_values(&["ERROR"]).get(),
+            6
+        );
+    }
+
+    #[test]
+    fn limited_when_full() {
+        //! Test that repeated events are still suppressed once the keys are full, and access lines never are
+        let config = RateLimitConfig {
+            burst: 2,
+            ..RateLimitConfig::default()
+        };
+        let rate_limit = RateLimit::new(&config);
+        let messages = Messages::default();
+        let subscriber =
+            tracing_subscriber::registry().with(messages.clone().with_filter(rate_limit.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            for request in 0..MAX_KEYS {
+                warn!("Request {} failed", request);
+            }
+            for _ in 0..5 {
+                warn!("Request {} failed", "again");
+                error!("Connection refused {}", "x".repeat(1000));
+                info!(target: "access", "GET / 200");
+            }
+        });
+        let messages = messages.0.lock().unwrap();
+        // A burst of each repeated event and every access line
+        assert_eq!(messages.len(), MAX_KEYS + 2 + 2 + 5);
+        assert_eq!(messages.iter().filter(|m| *m == "GET / 200").count(), 5);
+        assert_eq!(
+            rate_limit.suppressed().with_label_values(&["ERROR"]).get(),
+            3
+        );
+        assert_eq!(
+            rate_limit.suppressed().with_label_values(&["WARN"]).get(),
+            3
+        );
+        let state = rate_limit.state.lock().unwrap();
+        assert_eq!(state.entries.len(), MAX_KEYS + 2);
+        assert!(state
+            .entries
+            .values()
+            .all(|entry| entry.message.len() <= MAX_MESSAGE + 3));
+    }
+
+    #[test]
+    fn keyed_by_callsite() {
+        //! Test that events from one callsite share a limit whatever their message
+        let config = RateLimitConfig {
+            burst: 3,
+            key: RateLimitKey::Callsite,
+            ..RateLimitConfig::default()
+        };
+        let rate_limit = RateLimit::new(&config);
+        let messages = Messages::default();
+        let subscriber =
+            tracing_subscriber::registry().with(messages.clone().with_filter(rate_limit.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            for user in 0..10 {
+                warn!("User {} not found", user);
+            }
+        });
+        assert_eq!(messages.0.lock().unwrap().len(), 3);
+        assert_eq!(
+            rate_limit.suppressed().with_label_values(&["WARN"]).get(),
+            7
+        );
+        assert_eq!(RateLimitKey::parse("Message"), Some(RateLimitKey::Message));
+    }
+}
@@ -0,0 +1,364 @@
+//! Probes watching the resources used by the process
+//!
+//! Each [Resource] is measured at an interval against its limit and compared to a [Watermark].
+//! Crossing the warning level logs a warning while crossing the failure level stops the [HealthProbe] being [tick](HealthProbe::tick)ed, so the [HealthCheck](crate::k8slifecycle::HealthCheck) it is attached to fails.
+//!
+//! Measurements read `/proc` and the cgroup filesystem so are only available on Linux. Where a measurement is not available the probe stays valid.
+
+use crate::k8slifecycle::HealthProbe;
+use crate::{env_parse, spawn_component, HandleChannel};
+use serde::Serialize;
+use std::env;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+/// Warning and failure levels as a fraction of the limit of a [Resource]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Watermark {
+    /// Fraction of the limit above which a warning is logged
+    pub warn: f64,
+    /// Fraction of the limit above which the [HealthProbe] fails
+    pub fail: f64,
+}
+
+impl Default for Watermark {
+    fn default() -> Watermark {
+        Watermark {
+            warn: 0.8,
+            fail: 0.95,
+        }
+    }
+}
+
+impl Watermark {
+    /// Parse a [Watermark] from a `warn,fail` pair of fractions eg `0.8,0.95`
+    pub fn parse(value: &str) -> Option<Watermark> {
+        let (warn, fail) = value.split_once(',')?;
+        let warn = warn.trim().parse().ok()?;
+        let fail = fail.trim().parse().ok()?;
+        if warn <= fail {
+            Some(Watermark { warn, fail })
+        } else {
+            None
+        }
+    }
+}
+
+/// Configuration of the resource probes
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ResourceConfig {
+    /// Time between measurements
+    pub interval: Duration,
+    /// [Watermark] of resident memory against the cgroup memory limit
+    pub memory: Watermark,
+    /// [Watermark] of open file descriptors against RLIMIT_NOFILE
+    pub file_descriptors: Watermark,
+    /// [Watermark] of used disk space on each of the [ResourceConfig::disk_paths]
+    pub disk: Watermark,
+    /// Paths of the filesystems to check for free disk space
+    pub disk_paths: Vec<PathBuf>,
+}
+
+impl Default for ResourceConfig {
+    fn default() -> ResourceConfig {
+        ResourceConfig {
+            interval: Duration::from_secs(10),
+            memory: Watermark::default(),
+            file_descriptors: Watermark::default(),
+            disk: Watermark::default(),
+            disk_paths: Vec::new(),
+        }
+    }
+}
+
+impl ResourceConfig {
+    /// Create a [ResourceConfig] from defaults overridden by environment variables
+    ///
+    ///  * `USERVICE_RESOURCE_INTERVAL` seconds between measurements, greater than 0
+    ///  * `USERVICE_MEMORY_WATERMARK`, `USERVICE_FD_WATERMARK` and `USERVICE_DISK_WATERMARK` as `warn,fail` fractions
+    ///  * `USERVICE_DISK_PATHS` as a `:` separated list of paths
+    pub fn from_env() -> ResourceConfig {
+        let mut config = ResourceConfig::default();
+
+        if let Some(secs) = env_parse("USERVICE_RESOURCE_INTERVAL", |v| {
+            v.parse::<u64>().ok().filter(|secs| *secs > 0)
+        }) {
+            config.interval = Duration::from_secs(secs);
+        }
+        if let Some(watermark) = env_parse("USERVICE_MEMORY_WATERMARK", Watermark::parse) {
+            config.memory = watermark;
+        }
+        if let Some(watermark) = env_parse("USERVICE_FD_WATERMARK", Watermark::parse) {
+            config.file_descriptors = watermark;
+        }
+        if let Some(watermark) = env_parse("USERVICE_DISK_WATERMARK", Watermark::parse) {
+            config.disk = watermark;
+        }
+        if let Some(paths) = env::var_os("USERVICE_DISK_PATHS") {
+            config.disk_paths = env::split_paths(&paths).collect();
+        }
+        config
+    }
+}
+
+/// A resource of the process that can be measured against its limit
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resource {
+    /// Resident memory against the cgroup memory limit (or total memory if there is no limit)
+    Memory,
+    /// Open file descriptors against RLIMIT_NOFILE
+    FileDescriptors,
+    /// Used space on the filesystem holding the path
+    Disk(PathBuf),
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Memory => write!(f, "Memory"),
+            Resource::FileDescriptors => write!(f, "FileDescriptors"),
+            Resource::Disk(path) => write!(f, "Disk:{}", path.display()),
+        }
+    }
+}
+
+/// Amount used of a [Resource] against its limit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Usage {
+    /// Amount of the [Resource] in use
+    pub used: u64,
+    /// Maximum amount of the [Resource] available
+    pub limit: u64,
+}
+
+impl Usage {
+    /// Fraction of the limit that is used
+    pub fn ratio(&self) -> f64 {
+        if self.limit == 0 {
+            0.0
+        } else {
+            self.used as f64 / self.limit as f64
+        }
+    }
+}
+
+impl Resource {
+    /// Measure the current [Usage] of the [Resource], None if it cannot be measured on this system
+    pub fn usage(&self) -> io::Result<Option<Usage>> {
+        match self {
+            Resource::Memory => memory_usage(),
+            Resource::FileDescriptors => fd_usage(),
+            Resource::Disk(path) => disk_usage(path),
+        }
+    }
+}
+
+/// Read a file returning None if it does not exist
+fn read_optional(path: &str) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Find a `key: value kB` line in a `/proc` file and return the value in bytes
+fn parse_kb(content: &str, key: &str) -> Option<u64> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+/// Parse a cgroup memory limit where `max` or an unrealistically large value means no limit
+fn parse_cgroup_limit(content: &str) -> Option<u64> {
+    match content.trim().parse::<u64>() {
+        Ok(limit) if limit < 1 << 60 => Some(limit),
+        _ => None,
+    }
+}
+
+/// Parse the soft limit of open files from `/proc/self/limits`
+fn parse_nofile(content: &str) -> Option<u64> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("Max open files"))
+        .and_then(|limits| limits.split_whitespace().next()?.parse().ok())
+}
+
+fn memory_usage() -> io::Result<Option<Usage>> {
+    let used = match read_optional("/proc/self/status")?.and_then(|s| parse_kb(&s, "VmRSS")) {
+        Some(used) => used,
+        None => return Ok(None),
+    };
+
+    let mut limit = None;
+    for path in &[
+        "/sys/fs/cgroup/memory.max",
+        "/sys/fs/cgroup/memory/memory.limit_in_bytes",
+    ] {
+        if let Some(content) = read_optional(path)? {
+            limit = parse_cgroup_limit(&content);
+            break;
+        }
+    }
+    if limit.is_none() {
+        limit = read_optional("/proc/meminfo")?.and_then(|s| parse_kb(&s, "MemTotal"));
+    }
+
+    Ok(limit.map(|limit| Usage { used, limit }))
+}
+
+fn fd_usage() -> io::Result<Option<Usage>> {
+    let limit = match read_optional("/proc/self/limits")?.and_then(|s| parse_nofile(&s)) {
+        Some(limit) => limit,
+        None => return Ok(None),
+    };
+    let used = fs::read_dir("/proc/self/fd")?.count() as u64;
+    Ok(Some(Usage { used, limit }))
+}
+
+fn disk_usage(path: &Path) -> io::Result<Option<Usage>> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: cpath is a valid nul terminated string and stat is a valid statvfs to write to
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block = stat.f_frsize as u64;
+    let limit = stat.f_blocks as u64 * block;
+    let used = limit.saturating_sub(stat.f_bavail as u64 * block);
+    Ok(Some(Usage { used, limit }))
+}
+
+/// Start a probe of a [Resource] as a [HandleChannel] to be managed by the [UService](crate::UService)
+///
+/// The [HealthProbe] is ticked each `interval` while the [Usage] remains below the [Watermark::fail] level.
+pub async fn resource_probe(
+    resource: Resource,
+    probe: &HealthProbe,
+    watermark: Watermark,
+    interval: Duration,
+) -> HandleChannel {
+    info!("Starting resource probe: {}", resource);
+
+    let mut probe = probe.clone();
+    let (channel, mut rx) = mpsc::channel(1);
+
+    let name = format!("resource_probe {}", resource);
+    let handle = spawn_component(&name, async move {
+        loop {
+            match resource.usage() {
+                Ok(Some(usage)) => {
+                    let ratio = usage.ratio();
+                    if ratio >= watermark.fail {
+                        warn!(
+                            "{} usage {}/{} above failure level {}",
+                            resource, usage.used, usage.limit, watermark.fail
+                        );
+                    } else {
+                        if ratio >= watermark.warn {
+                            warn!(
+                                "{} usage {}/{} above warning level {}",
+                                resource, usage.used, usage.limit, watermark.warn
+                            );
+                        }
+                        probe.tick();
+                    }
+                }
+                Ok(None) => {
+                    debug!("{} usage not available", resource);
+                    probe.tick();
+                }
+                Err(e) => {
+                    warn!("Could not measure {}: {}", resource, e);
+                    probe.tick();
+                }
+            }
+
+            tokio::select! {
+                _ = sleep(interval) => {},
+                _ = rx.recv() => break,
+            }
+        }
+        info!("Resource probe closed: {}", resource);
+    });
+
+    HandleChannel { handle, channel }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resource_parsing() {
+        //! Test parsing of the /proc and cgroup formats
+        assert_eq!(
+            parse_kb("Name:\thello\nVmRSS:\t    1668 kB\n", "VmRSS"),
+            Some(1668 * 1024)
+        );
+        assert_eq!(parse_kb("VmHWM:\t 10 kB\n", "VmRSS"), None);
+        assert_eq!(parse_cgroup_limit("max\n"), None);
+        assert_eq!(parse_cgroup_limit("9223372036854771712\n"), None);
+        assert_eq!(parse_cgroup_limit("536870912\n"), Some(536870912));
+        assert_eq!(
+            parse_nofile(
+                "Max processes  63704  63704  processes\nMax open files  1024  4096  files\n"
+            ),
+            Some(1024)
+        );
+        assert_eq!(
+            Watermark::parse("0.7, 0.9"),
+            Some(Watermark {
+                warn: 0.7,
+                fail: 0.9
+            })
+        );
+        assert_eq!(Watermark::parse("0.9,0.7"), None);
+    }
+
+    #[tokio::test]
+    async fn resource_probe_watermarks() {
+        //! Test that a probe stays valid below the failure level and fails above it
+        let usage = Resource::FileDescriptors.usage().unwrap().unwrap();
+        assert!(usage.used > 0 && usage.used < usage.limit);
+        assert!(Resource::Disk(PathBuf::from("/"))
+            .usage()
+            .unwrap()
+            .is_some());
+
+        let low = HealthProbe::new("low", Duration::from_millis(50));
+        let high = HealthProbe::new("high", Duration::from_millis(50));
+        let relaxed = Watermark {
+            warn: 1.0,
+            fail: 1.0,
+        };
+        let strict = Watermark {
+            warn: 0.0,
+            fail: 0.0,
+        };
+        let interval = Duration::from_millis(10);
+        let low_probe = resource_probe(Resource::FileDescriptors, &low, relaxed, interval).await;
+        let high_probe = resource_probe(Resource::FileDescriptors, &high, strict, interval).await;
+
+        sleep(Duration::from_millis(100)).await;
+        assert!(low.valid());
+        assert!(!high.valid());
+
+        for probe in [low_probe, high_probe] {
+            probe.channel.send(()).await.unwrap();
+            probe.handle.await.unwrap();
+        }
+    }
+}
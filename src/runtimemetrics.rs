@@ -0,0 +1,302 @@
+//! Metrics of the tokio runtime
+//!
+//! [runtime_metrics] registers a collector that reads the [RuntimeMetrics] of a runtime each time the registry is gathered,
+//! so the worker count, alive tasks, queue depths and per worker busy time and parks are current at every scrape.
+//! Busy time growing with latency points to CPU saturation while flat busy time points to time spent waiting in handlers.
+//!
+//! Each gather builds its metric families from the totals of the runtime without keeping any state,
+//! so concurrent gathers by the scrape and the exporters all report the same totals.
+//!
+//! Blocking threads, local queue depths, poll and steal counts are only available in tokio with `--cfg tokio_unstable`,
+//! which `.cargo/config.toml` sets for every build. Setting `RUSTFLAGS` replaces it, so it must then include the cfg too.
+
+use crate::metrics::Metrics;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType};
+use std::collections::HashMap;
+use tokio::runtime::{Handle, RuntimeMetrics};
+use tracing::info;
+
+/// A series read from the runtime
+struct Series {
+    name: &'static str,
+    help: &'static str,
+    kind: MetricType,
+    /// Read for each worker with a `worker` label, rather than once for the runtime
+    per_worker: bool,
+    /// Read the value, of the given worker for per worker series
+    read: fn(&RuntimeMetrics, usize) -> f64,
+}
+
+const SERIES: &[Series] = &[
+    Series {
+        name: "tokio_workers",
+        help: "Number of runtime worker threads",
+        kind: MetricType::GAUGE,
+        per_worker: false,
+        read: |runtime, _| runtime.num_workers() as f64,
+    },
+    Series {
+        name: "tokio_alive_tasks",
+        help: "Number of tasks alive in the runtime",
+        kind: MetricType::GAUGE,
+        per_worker: false,
+        read: |runtime, _| runtime.num_alive_tasks() as f64,
+    },
+    Series {
+        name: "tokio_global_queue_depth",
+        help: "Number of tasks in the global queue of the runtime",
+        kind: MetricType::GAUGE,
+        per_worker: false,
+        read: |runtime, _| runtime.global_queue_depth() as f64,
+    },
+    Series {
+        name: "tokio_worker_busy_seconds_total",
+        help: "Time each worker has spent busy polling tasks",
+        kind: MetricType::COUNTER,
+        per_worker: true,
+        read: |runtime, worker| runtime.worker_total_busy_duration(worker).as_secs_f64(),
+    },
+    Series {
+        name: "tokio_worker_parks_total",
+        help: "Number of times each worker has parked waiting for work",
+        kind: MetricType::COUNTER,
+        per_worker: true,
+        read: |runtime, worker| runtime.worker_park_count(worker) as f64,
+    },
+];
+
+/// Series only available with `tokio_unstable`
+#[cfg(tokio_unstable)]
+const UNSTABLE_SERIES: &[Series] = &[
+    Series {
+        name: "tokio_blocking_threads",
+        help: "Number of threads in the blocking pool",
+        kind: MetricType::GAUGE,
+        per_worker: false,
+        read: |runtime, _| runtime.num_blocking_threads() as f64,
+    },
+    Series {
+        name: "tokio_idle_blocking_threads",
+        help: "Number of idle threads in the blocking pool",
+        kind: MetricType::GAUGE,
+        per_worker: false,
+        read: |runtime, _| runtime.num_idle_blocking_threads() as f64,
+    },
+    Series {
+        name: "tokio_blocking_queue_depth",
+        help: "Number of tasks waiting for a blocking thread",
+        kind: MetricType::GAUGE,
+        per_worker: false,
+        read: |runtime, _| runtime.blocking_queue_depth() as f64,
+    },
+    Series {
+        name: "tokio_spawned_tasks_total",
+        help: "Number of tasks spawned",
+        kind: MetricType::COUNTER,
+        per_worker: false,
+        read: |runtime, _| runtime.spawned_tasks_count() as f64,
+    },
+    Series {
+        name: "tokio_worker_local_queue_depth",
+        help: "Number of tasks in the local queue of each worker",
+        kind: MetricType::GAUGE,
+        per_worker: true,
+        read: |runtime, worker| runtime.worker_local_queue_depth(worker) as f64,
+    },
+    Series {
+        name: "tokio_worker_polls_total",
+        help: "Number of tasks polled by each worker",
+        kind: MetricType::COUNTER,
+        per_worker: true,
+        read: |runtime, worker| runtime.worker_poll_count(worker) as f64,
+    },
+    Series {
+        name: "tokio_worker_steals_total",
+        help: "Number of tasks each worker has stolen from other workers",
+        kind: MetricType::COUNTER,
+        per_worker: true,
+        read: |runtime, worker| runtime.worker_steal_count(worker) as f64,
+    },
+];
+
+/// Every series exported
+fn series() -> impl Iterator<Item = &'static Series> {
+    #[cfg(not(tokio_unstable))]
+    let series = SERIES.iter();
+    #[cfg(tokio_unstable)]
+    let series = SERIES.iter().chain(UNSTABLE_SERIES);
+    series
+}
+
+/// A metric with a value, labelled by worker if it is per worker
+fn metric(kind: MetricType, value: f64, worker: Option<usize>) -> Metric {
+    let mut metric = Metric::default();
+    if let Some(worker) = worker {
+        let mut label = LabelPair::default();
+        label.set_name(String::from("worker"));
+        label.set_value(worker.to_string());
+        metric.set_label(vec![label].into());
+    }
+    if kind == MetricType::COUNTER {
+        let mut counter = Counter::default();
+        counter.set_value(value);
+        metric.set_counter(counter);
+    } else {
+        let mut gauge = Gauge::default();
+        gauge.set_value(value);
+        metric.set_gauge(gauge);
+    }
+    metric
+}
+
+/// Collector of the metrics of a tokio runtime
+#[derive(Clone)]
+pub struct RuntimeCollector {
+    handle: Handle,
+    descs: Vec<Desc>,
+}
+
+impl RuntimeCollector {
+    /// Create a collector for the runtime of a [Handle]
+    pub fn new(handle: Handle) -> RuntimeCollector {
+        RuntimeCollector {
+            handle,
+            descs: series()
+                .map(|series| {
+                    let labels = if series.per_worker {
+                        vec![String::from("worker")]
+                    } else {
+                        Vec::new()
+                    };
+                    Desc::new(
+                        series.name.to_string(),
+                        series.help.to_string(),
+                        labels,
+                        HashMap::new(),
+                    )
+                    .expect("metric can be described")
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Collector for RuntimeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let runtime = self.handle.metrics();
+        series()
+            .map(|series| {
+                let metrics: Vec<Metric> = if series.per_worker {
+                    (0..runtime.num_workers())
+                        .map(|worker| {
+                            let value = (series.read)(&runtime, worker);
+                            metric(series.kind, value, Some(worker))
+                        })
+                        .collect()
+                } else {
+                    vec![metric(series.kind, (series.read)(&runtime, 0), None)]
+                };
+                let mut family = MetricFamily::default();
+                family.set_name(series.name.to_string());
+                family.set_help(series.help.to_string());
+                family.set_field_type(series.kind);
+                family.set_metric(metrics.into());
+                family
+            })
+            .collect()
+    }
+}
+
+/// Export the metrics of the runtime of a [Handle] to the [Metrics] of the service
+pub fn runtime_metrics(handle: &Handle, metrics: &Metrics) {
+    info!("Registering tokio runtime metrics");
+    metrics.register(&RuntimeCollector::new(handle.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn runtime_metrics_gathered() {
+        //! Test that the runtime metrics are read when the registry is gathered
+        let metrics = Metrics::new("runtime", "test");
+        runtime_metrics(&Handle::current(), &metrics);
+
+        let busy = tokio::spawn(async {
+            let start = std::time::Instant::now();
+            while start.elapsed() < Duration::from_millis(20) {}
+        });
+        busy.await.unwrap();
+        // Workers publish their statistics when they park
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let families = metrics.registry().gather();
+        let value = |name: &str| {
+            families
+                .iter()
+                .find(|family| family.get_name() == name)
+                .unwrap_or_else(|| panic!("{} gathered", name))
+                .get_metric()
+                .to_vec()
+        };
+        assert_eq!(value("tokio_workers")[0].get_gauge().get_value(), 2.0);
+        assert!(value("tokio_alive_tasks")[0].get_gauge().get_value() >= 0.0);
+        let busy: f64 = value("tokio_worker_busy_seconds_total")
+            .iter()
+            .map(|m| m.get_counter().get_value())
+            .sum();
+        assert!(busy >= 0.02);
+        assert_eq!(value("tokio_worker_parks_total").len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_gathers_agree() {
+        //! Test that concurrent gathers report the runtime totals rather than adding to each other
+        let collector = RuntimeCollector::new(Handle::current());
+        let runtime = Handle::current().metrics();
+        let total = || -> f64 {
+            (0..runtime.num_workers())
+                .map(|worker| runtime.worker_park_count(worker) as f64)
+                .sum()
+        };
+        // Workers park when idle, so there are parks to report
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let before = total();
+        assert!(before > 0.0);
+        let parks = |collector: &RuntimeCollector| -> f64 {
+            collector
+                .collect()
+                .iter()
+                .find(|family| family.get_name() == "tokio_worker_parks_total")
+                .unwrap()
+                .get_metric()
+                .iter()
+                .map(|m| m.get_counter().get_value())
+                .sum()
+        };
+        let gathers: Vec<_> = (0..8)
+            .map(|_| {
+                let collector = collector.clone();
+                std::thread::spawn(move || parks(&collector))
+            })
+            .collect();
+        let gathered: Vec<f64> = gathers.into_iter().map(|g| g.join().unwrap()).collect();
+        let after = total();
+        assert!(
+            gathered
+                .iter()
+                .all(|parks| (before..=after).contains(parks)),
+            "{:?} outside {}..={}",
+            gathered,
+            before,
+            after
+        );
+    }
+}
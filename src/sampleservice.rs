@@ -1,9 +1,12 @@
 //! Sample microservice demonstrating lifecycle hooks and small runtime loop with health probe included.
 
-use crate::HandleChannel;
+use crate::accesslog::{with_access_log, AccessLog};
+use crate::httpmetrics::with_metrics;
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::{spawn_component, HandleChannel};
 use tokio::sync::mpsc;
-use warp::Filter;
-use log::{info};
+use tracing::{info};
 
 mod filters {
     use super::handlers;
@@ -11,7 +14,7 @@ mod filters {
 
     pub fn sample(
         basepath: &'static str,
-    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
         warp::path(basepath).and(sample_1())
     }
 
@@ -25,7 +28,7 @@ mod filters {
 
 mod handlers {
     use std::convert::Infallible;
-    use log::{info};
+    use tracing::{info};
     use tokio::time::{sleep, Duration};
 
     pub async fn sample_h() -> Result<impl warp::Reply, Infallible> {
@@ -36,20 +39,20 @@ mod handlers {
     }
 }
 
-pub async fn sample_listen<'a>(basepath: &'static str, port: u16) -> HandleChannel {
+pub async fn sample_listen(basepath: &'static str, port: u16, metrics: &Metrics, access_log: &AccessLog) -> HandleChannel {
     info!("Starting sample service http on {}", port);
 
     let api = filters::sample(basepath);
 
-    let routes = api.with(warp::log("sample"));
+    let http_metrics = metrics.http("sample", &[format!("/{}/sample1", basepath)]);
+    let routes = with_access_log(with_metrics(api, http_metrics), access_log.clone(), "sample");
     let (channel, mut rx) = mpsc::channel(1);
 
-    let (_addr, server) =
-        warp::serve(routes).bind_with_graceful_shutdown(([0, 0, 0, 0], port), async move {
-            rx.recv().await;
-        });
+    let server = logging::serve(routes, port, async move {
+        rx.recv().await;
+    });
 
-    let handle = tokio::task::spawn(server);
+    let handle = spawn_component("sample_listen", server);
 
     HandleChannel { handle, channel }
 }
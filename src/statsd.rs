@@ -0,0 +1,387 @@
+//! Mirror metrics to [StatsD](https://github.com/statsd/statsd) or [DogStatsD](https://docs.datadoghq.com/developers/dogstatsd/) over UDP
+//!
+//! The registered metrics are gathered every flush interval and sent to the agent:
+//!
+//!  * counters as `c` with the increase since the previous flush
+//!  * gauges as `g`
+//!  * histograms as `<name>.count` and `<name>.sum` counters with the increase since the previous flush,
+//!    and their observations since the previous flush as `d` with DogStatsD or otherwise `ms` for durations
+//!    in seconds and `h` for other histograms
+//!
+//! Individual observations are not kept by the histograms, so the observations of each bucket are sent as one
+//! line at the upper bound of the bucket with a sample rate of one over their number, which the agent counts
+//! as that many observations. Observations above the highest bound are sent at the highest bound.
+//!
+//! With DogStatsD labels are sent as tags. Otherwise the values of labels other than the [CONST_LABELS] are
+//! appended to the metric name, so a series keeps its name across pods and releases.
+
+use crate::env_parse;
+use crate::metrics::{Metrics, CONST_LABELS};
+use crate::{spawn_component, HandleChannel};
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+/// Largest datagram sent, to stay within a typical network MTU
+const MAX_DATAGRAM: usize = 1432;
+
+/// Configuration of the StatsD exporter
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StatsdConfig {
+    /// Address of the agent eg `127.0.0.1:8125`
+    pub address: String,
+    /// Prefix added to each metric name, separated by a `.`
+    pub prefix: Option<String>,
+    /// Time between flushes
+    pub interval: Duration,
+    /// Send labels as DogStatsD tags
+    pub dogstatsd: bool,
+}
+
+impl StatsdConfig {
+    /// Create a [StatsdConfig] sending to an address
+    pub fn new(address: &str) -> StatsdConfig {
+        StatsdConfig {
+            address: address.to_string(),
+            prefix: None,
+            interval: Duration::from_secs(10),
+            dogstatsd: false,
+        }
+    }
+
+    /// Create a [StatsdConfig] from environment variables, if `USERVICE_STATSD_ADDRESS` is set
+    ///
+    ///  * `USERVICE_STATSD_ADDRESS` for the `host:port` of the agent
+    ///  * `USERVICE_STATSD_PREFIX` for the prefix of metric names
+    ///  * `USERVICE_STATSD_INTERVAL` for the seconds between flushes, greater than 0
+    ///  * `USERVICE_STATSD_DOGSTATSD` set to `true` to send labels as DogStatsD tags
+    pub fn from_env() -> Option<StatsdConfig> {
+        let defaults = StatsdConfig::new(&env::var("USERVICE_STATSD_ADDRESS").ok()?);
+        Some(StatsdConfig {
+            prefix: env::var("USERVICE_STATSD_PREFIX").ok(),
+            interval: env_parse("USERVICE_STATSD_INTERVAL", |v| {
+                v.parse().ok().filter(|secs| *secs > 0)
+            })
+            .map(Duration::from_secs)
+            .unwrap_or(defaults.interval),
+            dogstatsd: env_parse("USERVICE_STATSD_DOGSTATSD", |v| v.parse().ok())
+                .unwrap_or(defaults.dogstatsd),
+            ..defaults
+        })
+    }
+}
+
+/// Replace characters that have a meaning in the StatsD protocol
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | '#' | ',' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Converts gathered metrics to StatsD lines, remembering counter values to send increases
+struct Formatter {
+    config: StatsdConfig,
+    previous: HashMap<String, f64>,
+}
+
+impl Formatter {
+    fn new(config: StatsdConfig) -> Formatter {
+        Formatter {
+            config,
+            previous: HashMap::new(),
+        }
+    }
+
+    /// Name of a series and the DogStatsD tags suffix
+    fn series(&self, name: &str, metric: &Metric) -> (String, String) {
+        let mut series = match &self.config.prefix {
+            Some(prefix) => format!("{}.{}", prefix, name),
+            None => name.to_string(),
+        };
+        let mut tags = String::new();
+        for label in metric.get_label() {
+            if self.config.dogstatsd {
+                tags.push(if tags.is_empty() { '#' } else { ',' });
+                tags.push_str(&sanitize(label.get_name()));
+                tags.push(':');
+                tags.push_str(&sanitize(label.get_value()));
+            } else if !CONST_LABELS.contains(&label.get_name()) {
+                series.push('.');
+                series.push_str(&sanitize(label.get_value()).replace('.', "_"));
+            }
+        }
+        (sanitize(&series), tags)
+    }
+
+    /// Increase of a counter since the previous flush, treating a decrease as a reset
+    fn increase(&mut self, key: String, value: f64) -> f64 {
+        let previous = self.previous.insert(key, value).unwrap_or(0.0);
+        if value >= previous {
+            value - previous
+        } else {
+            value
+        }
+    }
+
+    fn line(name: &str, value: f64, kind: &str, tags: &str) -> String {
+        if tags.is_empty() {
+            format!("{}:{}|{}", name, value, kind)
+        } else {
+            format!("{}:{}|{}|{}", name, value, kind, tags)
+        }
+    }
+
+    /// Lines for the observations of each bucket of a histogram since the previous flush
+    fn observations(
+        &mut self,
+        family: &str,
+        name: &str,
+        metric: &Metric,
+        tags: &str,
+    ) -> Vec<String> {
+        let (kind, scale) = if self.config.dogstatsd {
+            ("d", 1.0)
+        } else if family.ends_with("_seconds") {
+            ("ms", 1000.0)
+        } else {
+            ("h", 1.0)
+        };
+        let histogram = metric.get_histogram();
+        let mut lines = Vec::new();
+        let mut below = 0;
+        let mut bound = 0.0;
+        let buckets = histogram
+            .get_bucket()
+            .iter()
+            .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()));
+        let overflow = (f64::INFINITY, histogram.get_sample_count());
+        for (upper_bound, cumulative) in buckets.chain(std::iter::once(overflow)) {
+            if upper_bound.is_finite() {
+                bound = upper_bound;
+            }
+            let count = cumulative.saturating_sub(below) as f64;
+            below = cumulative;
+            let key = format!("{}|{}|{}", name, tags, upper_bound);
+            let increase = self.increase(key, count);
+            if increase >= 1.0 {
+                let kind = format!("{}|@{}", kind, 1.0 / increase);
+                lines.push(Formatter::line(name, bound * scale, &kind, tags));
+            }
+        }
+        lines
+    }
+
+    fn lines(&mut self, families: &[MetricFamily]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for family in families {
+            for metric in family.get_metric() {
+                let (name, tags) = self.series(family.get_name(), metric);
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let key = format!("{}|{}", name, tags);
+                        let increase = self.increase(key, metric.get_counter().get_value());
+                        lines.push(Formatter::line(&name, increase, "c", &tags));
+                    }
+                    MetricType::GAUGE => {
+                        let value = metric.get_gauge().get_value();
+                        lines.push(Formatter::line(&name, value, "g", &tags));
+                    }
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        for (suffix, value) in [
+                            ("count", histogram.get_sample_count() as f64),
+                            ("sum", histogram.get_sample_sum()),
+                        ] {
+                            let series = format!("{}.{}", name, suffix);
+                            let increase = self.increase(format!("{}|{}", series, tags), value);
+                            lines.push(Formatter::line(&series, increase, "c", &tags));
+                        }
+                        lines.extend(self.observations(family.get_name(), &name, metric, &tags));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// Join lines into datagrams no larger than [MAX_DATAGRAM]
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
+
+async fn flush(socket: &UdpSocket, formatter: &mut Formatter, metrics: &Metrics) {
+    let mut families = metrics.registry().gather();
+    families.extend(prometheus::gather());
+    let lines = formatter.lines(&families);
+    for datagram in datagrams(&lines) {
+        if let Err(e) = socket.send(datagram.as_bytes()).await {
+            warn!("Could not send metrics to StatsD: {}", e);
+            return;
+        }
+    }
+    debug!("Sent {} metrics to StatsD", lines.len());
+}
+
+/// Start mirroring metrics to StatsD as a [HandleChannel] to be managed by the [UService](crate::UService)
+///
+/// Metrics are flushed every interval and a final time when the shutdown signal is received.
+pub async fn statsd_exporter(config: &StatsdConfig, metrics: &Metrics) -> HandleChannel {
+    info!("Starting StatsD exporter to {}", config.address);
+
+    let config = config.clone();
+    let metrics = metrics.clone();
+    let (channel, mut rx) = mpsc::channel(1);
+
+    let handle = spawn_component("statsd_exporter", async move {
+        let socket = match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("StatsD exporter could not bind a socket: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = socket.connect(&config.address).await {
+            warn!(
+                "StatsD exporter could not resolve {}: {}",
+                config.address, e
+            );
+            return;
+        }
+
+        let interval = config.interval;
+        let mut formatter = Formatter::new(config);
+        loop {
+            tokio::select! {
+                _ = sleep(interval) => {},
+                _ = rx.recv() => break,
+            }
+            flush(&socket, &mut formatter, &metrics).await;
+        }
+
+        flush(&socket, &mut formatter, &metrics).await;
+        info!("StatsD exporter closed");
+    });
+
+    HandleChannel { handle, channel }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{HistogramOpts, HistogramVec, IntCounter, Registry};
+
+    #[test]
+    fn statsd_lines() {
+        //! Test that counters send increases and labels become tags or name segments
+        let registry = Registry::new();
+        let counter = IntCounter::new("jobs", "Jobs run").unwrap();
+        let histogram =
+            HistogramVec::new(HistogramOpts::new("latency_seconds", "Latency"), &["route"])
+                .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        counter.inc_by(3);
+        histogram.with_label_values(&["/a.b"]).observe(0.5);
+        histogram.with_label_values(&["/a.b"]).observe(0.4);
+        histogram.with_label_values(&["/a.b"]).observe(0.02);
+
+        let mut config = StatsdConfig::new("127.0.0.1:8125");
+        config.prefix = Some(String::from("shop"));
+        let mut plain = Formatter::new(config.clone());
+        config.dogstatsd = true;
+        let mut dog = Formatter::new(config);
+
+        let lines = dog.lines(&registry.gather());
+        assert!(lines.contains(&String::from("shop.jobs:3|c")));
+        assert!(lines.contains(&String::from("shop.latency_seconds.count:3|c|#route:/a.b")));
+        assert!(lines.contains(&String::from("shop.latency_seconds.sum:0.92|c|#route:/a.b")));
+        assert!(lines.contains(&String::from("shop.latency_seconds:0.5|d|@0.5|#route:/a.b")));
+        assert!(lines.contains(&String::from("shop.latency_seconds:0.025|d|@1|#route:/a.b")));
+        let lines = plain.lines(&registry.gather());
+        assert!(lines.contains(&String::from("shop.latency_seconds./a_b.count:3|c")));
+        assert!(lines.contains(&String::from("shop.latency_seconds./a_b:500|ms|@0.5")));
+        assert!(plain
+            .lines(&registry.gather())
+            .iter()
+            .all(|line| !line.contains('@')));
+
+        counter.inc();
+        assert!(dog
+            .lines(&registry.gather())
+            .contains(&String::from("shop.jobs:1|c")));
+
+        let versioned = Registry::new_custom(
+            None,
+            Some(HashMap::from([(
+                String::from("version"),
+                String::from("0.1.0"),
+            )])),
+        )
+        .unwrap();
+        versioned.register(Box::new(histogram.clone())).unwrap();
+        assert!(plain
+            .lines(&versioned.gather())
+            .iter()
+            .all(|line| line.starts_with("shop.latency_seconds./a_b")));
+
+        let long: Vec<String> = (0..200).map(|i| format!("metric{}:1|c", i)).collect();
+        let packed = datagrams(&long);
+        assert!(packed.len() > 1 && packed.iter().all(|d| d.len() <= MAX_DATAGRAM));
+        assert_eq!(packed.join("\n"), long.join("\n"));
+    }
+
+    #[tokio::test]
+    async fn statsd_final_flush() {
+        //! Test that the exporter sends to the agent when shut down
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let metrics = Metrics::new("statsd", "test");
+        metrics.counter("jobs", "Jobs run").unwrap().inc();
+        let mut config = StatsdConfig::new(&agent.local_addr().unwrap().to_string());
+        config.interval = Duration::from_secs(3600);
+        config.dogstatsd = true;
+
+        let exporter = statsd_exporter(&config, &metrics).await;
+        exporter.channel.send(()).await.unwrap();
+        exporter.handle.await.unwrap();
+
+        let mut received = String::new();
+        let mut buffer = [0; MAX_DATAGRAM];
+        while let Ok(Ok(len)) =
+            tokio::time::timeout(Duration::from_millis(100), agent.recv(&mut buffer)).await
+        {
+            received.push_str(std::str::from_utf8(&buffer[..len]).unwrap());
+            received.push('\n');
+        }
+        let line = received
+            .lines()
+            .find(|line| line.starts_with("jobs:1|c|#"))
+            .expect("counter sent");
+        assert!(line.contains("service:statsd"));
+    }
+}